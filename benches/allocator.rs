@@ -0,0 +1,96 @@
+//! Cost of handing out one address against the occupancy of a /8 pool.
+//!
+//! `pool` is `Ipv4::next_free`, the bitmap behind the pool plus the lookup
+//! of the address. `retry` draws random addresses until it hits a free one,
+//! as the pool used to, and slows down as the pool fills up. `mapping` is a
+//! whole `Mapping::get_or_insert` of a new name, evicting once the pool is
+//! full.
+
+use std::{collections::HashSet, hint::black_box, net::Ipv4Addr};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use fake_dns::{
+    mapping::Mapping,
+    pool::{Ipv4, Pool},
+};
+use hickory_resolver::proto::rr::Name;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+const CIDR: &str = "10.0.0.0/8";
+
+/// A /8 pool and its used addresses, `occupancy`% of them.
+fn filled(occupancy: u32, rng: &mut StdRng) -> (Ipv4, HashSet<Ipv4Addr>) {
+    let mut pool = Ipv4::from_cidr(CIDR).unwrap();
+    let mut used = HashSet::new();
+    for n in 0..pool.capacity() {
+        if rng.gen_ratio(occupancy, 100) {
+            let ip = pool.nth(n);
+            pool.set_used(ip, true);
+            used.insert(ip);
+        }
+    }
+    (pool, used)
+}
+
+fn allocate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocate");
+    let mut rng = StdRng::seed_from_u64(7);
+    for occupancy in [0, 50, 90, 95, 99] {
+        let (mut pool, used) = filled(occupancy, &mut rng);
+        let capacity = pool.capacity();
+
+        // released right away so the occupancy stays put
+        group.bench_function(BenchmarkId::new("pool", occupancy), |b| {
+            b.iter(|| {
+                let start = rng.gen_range(0..capacity);
+                let ip = pool.next_free(black_box(start), |_| false).unwrap();
+                pool.set_used(ip, true);
+                pool.set_used(ip, false);
+                ip
+            })
+        });
+        group.bench_function(BenchmarkId::new("retry", occupancy), |b| {
+            b.iter(|| {
+                loop {
+                    let ip = pool.nth(rng.gen_range(0..capacity));
+                    if !used.contains(black_box(&ip)) {
+                        break ip;
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn mapping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_or_insert");
+    // a /20 keeps filling the table quick
+    let mut mapping = Mapping::new(Ipv4::from_cidr("10.0.0.0/20").unwrap());
+    let capacity = mapping.capacity() as usize;
+    let names = (0..capacity * 3)
+        .map(|i| Name::from_ascii(format!("{i}.example.com.")).unwrap())
+        .collect::<Vec<_>>();
+    // `remove` first keeps the occupancy put, without it every name of the
+    // last round was evicted a round earlier and evicts another one
+    for (label, range, remove) in [
+        ("half_full", 0..capacity / 2, true),
+        ("full", capacity / 2..capacity, true),
+        ("evicting", capacity..capacity * 3, false),
+    ] {
+        let mut names = names[range].iter().cycle();
+        group.bench_function(label, |b| {
+            b.iter(|| {
+                let name = names.next().unwrap();
+                if remove {
+                    mapping.remove(name);
+                }
+                mapping.get_or_insert(black_box(name)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, allocate, mapping);
+criterion_main!(benches);
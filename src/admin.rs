@@ -0,0 +1,404 @@
+use std::{convert::Infallible, net::IpAddr, ops::Range, sync::Arc};
+
+use hickory_resolver::proto::rr::Name;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+use crate::{
+    DEFAULT_POOL, MyError, Server, metrics,
+    rules::{self, Action},
+};
+
+/// Page size of `GET /mappings` when `limit` is not given.
+const DEFAULT_LIMIT: usize = 100;
+
+/// Serves the HTTP/JSON admin API:
+///
+/// - `GET /mappings?offset=&limit=&filter=&pinned=` lists mappings, least
+///   recently used first, `filter` matches a part of the domain
+/// - `GET /mappings/<domain>` and `GET /ips/<ip>` look a mapping up
+/// - `PUT /mappings/<domain>` pins the domain, optionally to the address in
+///   a `{"ip": ...}` body, otherwise in the pool its rule draws from
+/// - `DELETE /mappings/<domain>` drops the domain
+/// - `DELETE /mappings?pinned=true` flushes the pools, pinned mappings are
+///   kept unless `pinned` is set
+/// - `GET /metrics` exports [`metrics`] for Prometheus
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request| handle(request, server.clone()));
+                    if let Err(e) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        warn!("admin connection failed {:?}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("failed to accept admin connection {:?}", e),
+        }
+    }
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    server: Arc<Server>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (parts, body) = request.into_parts();
+    if parts.method == Method::GET && parts.uri.path() == "/metrics" {
+        let mut response = Response::new(Full::new(Bytes::from(metrics::render(&server))));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+        return Ok(response);
+    }
+
+    let (status, body) = match body.collect().await {
+        Ok(body) => route(
+            &server,
+            &parts.method,
+            parts.uri.path(),
+            parts.uri.query().unwrap_or_default(),
+            &body.to_bytes(),
+        ),
+        Err(e) => error(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    Ok(response)
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Row {
+    domain: String,
+    ip: IpAddr,
+    pinned: bool,
+    pool: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pin {
+    ip: Option<IpAddr>,
+}
+
+fn route(
+    server: &Server,
+    method: &Method,
+    path: &str,
+    query: &str,
+    body: &[u8],
+) -> (StatusCode, Value) {
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let param = |key: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    };
+
+    match (method, &segments[..]) {
+        (&Method::GET, ["mappings"]) => {
+            let (Ok(offset), Ok(limit)) = (
+                param("offset").map_or(Ok(0), str::parse::<usize>),
+                param("limit").map_or(Ok(DEFAULT_LIMIT), str::parse::<usize>),
+            ) else {
+                return error(StatusCode::BAD_REQUEST, "invalid offset or limit");
+            };
+            let filter = param("filter").unwrap_or_default().to_lowercase();
+            let pinned = param("pinned").map(|v| v == "true");
+
+            let (total, page) = rows(
+                server,
+                &filter,
+                pinned,
+                offset..offset.saturating_add(limit),
+            );
+            (StatusCode::OK, json!({ "total": total, "mappings": page }))
+        }
+        (&Method::DELETE, ["mappings"]) => {
+            let pinned = param("pinned") == Some("true");
+            let mut flushed = 0;
+            for (_, mapping) in server.mappings() {
+                flushed += mapping.lock().unwrap().flush(pinned);
+            }
+            if let Some(mapping6) = &server.mapping6 {
+                flushed += mapping6.lock().unwrap().flush(pinned);
+            }
+            info!("flushed {} mappings", flushed);
+            (StatusCode::OK, json!({ "flushed": flushed }))
+        }
+        (method, ["mappings", domain]) => {
+            let name = match rules::fqdn(domain) {
+                Ok(name) => name,
+                Err(e) => return error(StatusCode::BAD_REQUEST, e),
+            };
+            match *method {
+                Method::GET => match lookup(server, &name) {
+                    rows if rows.is_empty() => error(StatusCode::NOT_FOUND, "no such mapping"),
+                    rows => (StatusCode::OK, json!(rows)),
+                },
+                Method::PUT => {
+                    let request = match body {
+                        [] => Pin::default(),
+                        body => match serde_json::from_slice(body) {
+                            Ok(pin) => pin,
+                            Err(e) => return error(StatusCode::BAD_REQUEST, e),
+                        },
+                    };
+                    match pin(server, &name, request.ip) {
+                        Ok(()) => (StatusCode::OK, json!(lookup(server, &name))),
+                        Err(e) => error(StatusCode::CONFLICT, e),
+                    }
+                }
+                Method::DELETE => {
+                    let rows = lookup(server, &name);
+                    for (_, mapping) in server.mappings() {
+                        mapping.lock().unwrap().remove(&name);
+                    }
+                    if let Some(mapping6) = &server.mapping6 {
+                        mapping6.lock().unwrap().remove(&name);
+                    }
+                    if rows.is_empty() {
+                        error(StatusCode::NOT_FOUND, "no such mapping")
+                    } else {
+                        (StatusCode::OK, json!(rows))
+                    }
+                }
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+            }
+        }
+        (&Method::GET, ["ips", ip]) => {
+            let Ok(ip) = ip.parse::<IpAddr>() else {
+                return error(StatusCode::BAD_REQUEST, "invalid ip address");
+            };
+            match server.domain(ip).flatten() {
+                Some(domain) => {
+                    match lookup(server, &domain).into_iter().find(|row| row.ip == ip) {
+                        Some(row) => (StatusCode::OK, json!(row)),
+                        None => error(StatusCode::NOT_FOUND, "no such mapping"),
+                    }
+                }
+                None => error(StatusCode::NOT_FOUND, "no such mapping"),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    }
+}
+
+fn error(status: StatusCode, e: impl ToString) -> (StatusCode, Value) {
+    (status, json!({ "error": e.to_string() }))
+}
+
+/// The mappings of every pool whose domain contains `filter` and, if given,
+/// whose pin state is `pinned`. Returns how many match and the matches
+/// falling into `page`, without building rows for the others.
+fn rows(
+    server: &Server,
+    filter: &str,
+    pinned: Option<bool>,
+    page: Range<usize>,
+) -> (usize, Vec<Row>) {
+    let mut total = 0;
+    let mut rows = Vec::new();
+    let mut visit = |name: &Name, ip: IpAddr, is_pinned: bool, pool: &str| {
+        if pinned.is_some_and(|pinned| pinned != is_pinned)
+            || !(filter.is_empty() || name.to_string().contains(filter))
+        {
+            return;
+        }
+        if page.contains(&total) {
+            rows.push(Row {
+                domain: name.to_string(),
+                ip,
+                pinned: is_pinned,
+                pool: pool.to_string(),
+            });
+        }
+        total += 1;
+    };
+    for (pool, mapping) in server.mappings() {
+        for (name, entry) in mapping.lock().unwrap().entries() {
+            visit(name, entry.ip.into(), entry.pinned, pool);
+        }
+    }
+    if let Some(mapping6) = &server.mapping6 {
+        for (name, entry) in mapping6.lock().unwrap().entries() {
+            visit(name, entry.ip.into(), entry.pinned, DEFAULT_POOL);
+        }
+    }
+    (total, rows)
+}
+
+/// The mappings of `name`, one per pool.
+fn lookup(server: &Server, name: &Name) -> Vec<Row> {
+    let row = |ip: IpAddr, pinned: bool, pool: &str| Row {
+        domain: name.to_string(),
+        ip,
+        pinned,
+        pool: pool.to_string(),
+    };
+    let mut rows = Vec::new();
+    for (pool, mapping) in server.mappings() {
+        if let Some(entry) = mapping.lock().unwrap().get(name) {
+            rows.push(row(entry.ip.into(), entry.pinned, pool));
+        }
+    }
+    if let Some(mapping6) = &server.mapping6
+        && let Some(entry) = mapping6.lock().unwrap().get(name)
+    {
+        rows.push(row(entry.ip.into(), entry.pinned, DEFAULT_POOL));
+    }
+    rows
+}
+
+/// Pins `name` to `ip`, in the IPv4 pool holding `ip`. Without an address
+/// `name` is pinned in the pool its rule draws from, the default pools of
+/// both families unless that is a named pool.
+fn pin(server: &Server, name: &Name, ip: Option<IpAddr>) -> Result<(), MyError> {
+    match ip {
+        Some(IpAddr::V4(ip)) => {
+            let mapping = server
+                .mappings()
+                .find(|(_, mapping)| mapping.lock().unwrap().contains(ip))
+                .map_or(&server.mapping, |(_, mapping)| mapping);
+            mapping.lock().unwrap().pin(name, Some(ip))?;
+        }
+        Some(IpAddr::V6(ip)) => {
+            let mapping6 = server
+                .mapping6
+                .as_ref()
+                .ok_or_else(|| MyError::Mapping("no ipv6 pool configured".to_string()))?;
+            mapping6.lock().unwrap().pin(name, Some(ip))?;
+        }
+        None => match server.settings().rules.action(name).0 {
+            Action::Pool(pool) => {
+                server.pool(Some(pool))?.lock().unwrap().pin(name, None)?;
+            }
+            _ => {
+                server.mapping.lock().unwrap().pin(name, None)?;
+                if let Some(mapping6) = &server.mapping6 {
+                    mapping6.lock().unwrap().pin(name, None)?;
+                }
+            }
+        },
+    }
+    info!("pinned {}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::route;
+    use crate::{
+        mapping::Mapping,
+        pool::Ipv4,
+        tests::{server, set_rules},
+    };
+
+    #[test]
+    fn inspect_and_edit_mappings() {
+        let mut server = server("10.0.0.0/24");
+        let tenant = Ipv4::from_cidr("100.64.0.0/24").unwrap();
+        server
+            .pools
+            .insert("tenant-a".to_string(), Mutex::new(Mapping::new(tenant)));
+        set_rules(&server, "suffix:corp.lan fake:tenant-a");
+        let call = |method: Method, path: &str, query: &str, body: &str| {
+            route(&server, &method, path, query, body.as_bytes())
+        };
+
+        let (status, _) = call(
+            Method::PUT,
+            "/mappings/a.example.com",
+            "",
+            r#"{"ip": "10.0.0.5"}"#,
+        );
+        assert_eq!(status, StatusCode::OK);
+        call(Method::PUT, "/mappings/b.example.com", "", "");
+        call(Method::PUT, "/mappings/c.example.org", "", "");
+
+        let (status, body) = call(Method::GET, "/ips/10.0.0.5", "", "");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "domain": "a.example.com.",
+                "ip": "10.0.0.5",
+                "pinned": true,
+                "pool": "default"
+            })
+        );
+        let (_, body) = call(Method::GET, "/mappings/A.example.com.", "", "");
+        assert_eq!(body[0]["ip"], "10.0.0.5");
+
+        let (_, body) = call(
+            Method::GET,
+            "/mappings",
+            "filter=example.com&limit=1&offset=1",
+            "",
+        );
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["mappings"][0]["domain"], "b.example.com.");
+        let (_, body) = call(Method::GET, "/mappings", "pinned=true&limit=0", "");
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["mappings"], json!([]));
+
+        let (status, _) = call(
+            Method::PUT,
+            "/mappings/d.example.com",
+            "",
+            r#"{"ip": "10.0.1.1"}"#,
+        );
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // pinned in the pool of its rule
+        call(Method::PUT, "/mappings/git.corp.lan", "", "");
+        let (_, body) = call(Method::GET, "/mappings/git.corp.lan", "", "");
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["pool"], "tenant-a");
+
+        let (status, _) = call(Method::DELETE, "/mappings/b.example.com", "", "");
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(Method::GET, "/mappings/b.example.com", "", "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        server
+            .mapping
+            .lock()
+            .unwrap()
+            .get_or_insert(&"e.example.com.".parse().unwrap())
+            .unwrap();
+        let (_, body) = call(Method::DELETE, "/mappings", "", "");
+        assert_eq!(body["flushed"], 1);
+        let (_, body) = call(Method::DELETE, "/mappings", "pinned=true", "");
+        assert_eq!(body["flushed"], 3);
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hasher,
+    net::SocketAddr,
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use hickory_resolver::proto::rr::Name;
+use rand::Rng;
+use serde::Deserialize;
+use siphasher::sip::SipHasher24;
+
+use crate::{
+    MyError,
+    pool::{Ipv4, Ipv6, Pool},
+};
+
+/// The built-in allocators: [`Random`], [`Sequential`], [`Hashed`] and
+/// [`Lru`]. The variant docs double as `--help` text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocationMode {
+    /// Any free address, picked at random.
+    #[default]
+    Random,
+    /// The next free address after the last one handed out.
+    Sequential,
+    /// Derived from a keyed hash of the name, replicas agree.
+    Hash,
+    /// The address released longest ago, so cached ones are reused last.
+    Lru,
+}
+
+/// What a new address is allocated for.
+pub struct Context<'a> {
+    pub name: &'a Name,
+    /// `None` when no query asked for it, e.g. when pinning through the
+    /// admin API.
+    pub client: Option<SocketAddr>,
+}
+
+/// Picks the address of a name seen for the first time.
+///
+/// [`Mapping`](crate::mapping::Mapping) calls it only while the pool has a
+/// free address, evicting a mapping first when it is full, and rejects an
+/// address that is taken or outside the pool.
+pub trait Allocator<P: Pool>: Send {
+    /// A free address of `pool`, `used` tells the taken ones apart.
+    fn allocate(
+        &mut self,
+        pool: &P,
+        used: &dyn Fn(&P::Addr) -> bool,
+        context: &Context,
+    ) -> Result<P::Addr, MyError>;
+
+    /// `ip` was given back to the pool.
+    fn release(&mut self, _ip: P::Addr) {}
+}
+
+/// Hands every pool its own allocator when the server starts, see
+/// [`run_with`](crate::run_with).
+pub trait Allocators: Sync {
+    /// Allocator of the IPv4 pool `pool`, `default` for the default one.
+    fn ipv4(&self, pool: &str) -> Box<dyn Allocator<Ipv4>>;
+
+    /// Allocator of the IPv6 pool.
+    fn ipv6(&self) -> Box<dyn Allocator<Ipv6>>;
+}
+
+/// A random address, or the next free one after it.
+pub struct Random;
+
+impl<P: Pool> Allocator<P> for Random {
+    fn allocate(
+        &mut self,
+        pool: &P,
+        used: &dyn Fn(&P::Addr) -> bool,
+        _: &Context,
+    ) -> Result<P::Addr, MyError> {
+        let start = rand::thread_rng().gen_range(0..pool.capacity());
+        pool.next_free(start, used).ok_or(MyError::IpNotEnough)
+    }
+}
+
+/// The next free address after the one handed out last, wrapping around.
+#[derive(Default)]
+pub struct Sequential {
+    next: u128,
+}
+
+impl<P: Pool> Allocator<P> for Sequential {
+    fn allocate(
+        &mut self,
+        pool: &P,
+        used: &dyn Fn(&P::Addr) -> bool,
+        _: &Context,
+    ) -> Result<P::Addr, MyError> {
+        let ip = pool
+            .next_free(self.next % pool.capacity(), used)
+            .ok_or(MyError::IpNotEnough)?;
+        self.next = pool.index(ip).map_or(0, |n| n + 1);
+        Ok(ip)
+    }
+}
+
+/// The address at the SipHash-2-4 of the lowercased name, or the next free
+/// one after it. Replicas sharing the key and the pools hand out the same
+/// addresses without sharing state, unless names collide or the pool fills
+/// up and evicts.
+pub struct Hashed {
+    key: HashKey,
+}
+
+impl Hashed {
+    pub fn new(key: HashKey) -> Self {
+        Self { key }
+    }
+}
+
+impl<P: Pool> Allocator<P> for Hashed {
+    fn allocate(
+        &mut self,
+        pool: &P,
+        used: &dyn Fn(&P::Addr) -> bool,
+        context: &Context,
+    ) -> Result<P::Addr, MyError> {
+        let start = self.key.hash(context.name) as u128 % pool.capacity();
+        pool.next_free(start, used).ok_or(MyError::IpNotEnough)
+    }
+}
+
+/// Addresses never handed out first, then the one given back the longest
+/// time ago, so that an address lingering in client caches is reused last.
+pub struct Lru<A> {
+    /// Index of the next address never handed out, the capacity once all
+    /// have been.
+    fresh: u128,
+    /// Release tick -> address, oldest first.
+    released: BTreeMap<u64, A>,
+    /// Address -> its tick in `released`, an address given back again moves
+    /// to the back instead of showing up twice.
+    ticks: HashMap<A, u64>,
+    tick: u64,
+}
+
+impl<A> Default for Lru<A> {
+    fn default() -> Self {
+        Self {
+            fresh: 0,
+            released: BTreeMap::new(),
+            ticks: HashMap::new(),
+            tick: 0,
+        }
+    }
+}
+
+impl<P: Pool> Allocator<P> for Lru<P::Addr> {
+    fn allocate(
+        &mut self,
+        pool: &P,
+        used: &dyn Fn(&P::Addr) -> bool,
+        _: &Context,
+    ) -> Result<P::Addr, MyError> {
+        if self.fresh < pool.capacity()
+            && let Some(ip) = pool.next_free(self.fresh, used)
+            && let Some(n) = pool.index(ip).filter(|n| *n >= self.fresh)
+        {
+            self.fresh = n + 1;
+            return Ok(ip);
+        }
+        self.fresh = pool.capacity();
+        // entries taken again since, by a pin for example, are dropped
+        while let Some((_, ip)) = self.released.pop_first() {
+            self.ticks.remove(&ip);
+            if !used(&ip) {
+                return Ok(ip);
+            }
+        }
+        pool.next_free(0, used).ok_or(MyError::IpNotEnough)
+    }
+
+    fn release(&mut self, ip: P::Addr) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(ip, self.tick) {
+            self.released.remove(&tick);
+        }
+        self.released.insert(self.tick, ip);
+    }
+}
+
+/// 128 bit SipHash key, written as 32 hex digits.
+#[derive(Clone, Copy, PartialEq)]
+pub struct HashKey([u8; 16]);
+
+impl HashKey {
+    fn hash(&self, name: &Name) -> u64 {
+        let mut hasher = SipHasher24::new_with_key(&self.0);
+        hasher.write(name.to_lowercase().to_ascii().as_bytes());
+        hasher.finish()
+    }
+}
+
+impl FromStr for HashKey {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 32 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(MyError::Config(
+                "the hash key must be 32 hex digits".to_string(),
+            ));
+        }
+        let mut key = [0; 16];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap_or_default();
+        }
+        Ok(Self(key))
+    }
+}
+
+/// Keeps the key out of logs.
+impl fmt::Debug for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HashKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use hickory_resolver::proto::rr::Name;
+
+    use super::{Allocator, Context, HashKey, Hashed, Lru, Sequential};
+    use crate::{
+        MyError,
+        mapping::Mapping,
+        pool::{Ipv4, Pool},
+        tests::CLIENT,
+    };
+
+    fn name(i: usize) -> Name {
+        Name::from_ascii(format!("{i}.example.com.")).unwrap()
+    }
+
+    fn ip(ip: &str) -> Ipv4Addr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn sequential_in_order() {
+        let pool = Ipv4::from_cidr("10.0.0.0/29").unwrap();
+        let mut mapping = Mapping::with_allocator(pool, Box::new(Sequential::default()));
+        mapping.pin(&name(9), Some(ip("10.0.0.2"))).unwrap();
+        let ips = (0..3)
+            .map(|i| mapping.get_or_insert(&name(i)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ips, [ip("10.0.0.1"), ip("10.0.0.3"), ip("10.0.0.4")]);
+
+        mapping.remove(&name(0));
+        assert_eq!(mapping.get_or_insert(&name(3)).unwrap(), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn lru_reuses_the_oldest_released() {
+        let pool = Ipv4::from_cidr("10.0.0.0/29").unwrap();
+        let mut mapping = Mapping::with_allocator(pool, Box::new(Lru::default()));
+        for i in 0..4 {
+            mapping.get_or_insert(&name(i)).unwrap();
+        }
+        mapping.remove(&name(2));
+        mapping.remove(&name(0));
+        // fresh addresses go first
+        assert_eq!(mapping.get_or_insert(&name(4)).unwrap(), ip("10.0.0.5"));
+        assert_eq!(mapping.get_or_insert(&name(5)).unwrap(), ip("10.0.0.6"));
+        assert_eq!(mapping.get_or_insert(&name(6)).unwrap(), ip("10.0.0.3"));
+        assert_eq!(mapping.get_or_insert(&name(7)).unwrap(), ip("10.0.0.1"));
+        // full, the least recently used name makes room
+        assert_eq!(mapping.get_or_insert(&name(8)).unwrap(), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn hashed_addresses_agree_across_replicas() {
+        let key = "000102030405060708090a0b0c0d0e0f"
+            .parse::<HashKey>()
+            .unwrap();
+        let replica = || {
+            Mapping::with_allocator(
+                Ipv4::from_cidr("10.0.0.0/16").unwrap(),
+                Box::new(Hashed::new(key)),
+            )
+        };
+        let names = ["a.example.com.", "b.example.com.", "c.example.org."]
+            .map(|name| Name::from_ascii(name).unwrap());
+
+        let (mut one, mut two) = (replica(), replica());
+        let ips = names
+            .each_ref()
+            .map(|name| one.get_or_insert(name).unwrap());
+        for name in names.iter().rev() {
+            two.get_or_insert(name).unwrap();
+        }
+        let upper = Name::from_ascii("A.Example.COM.").unwrap();
+        assert_eq!(replica().get_or_insert(&upper).unwrap(), ips[0]);
+        for (name, ip) in names.iter().zip(ips) {
+            assert_eq!(two.get(name).unwrap().ip, ip);
+        }
+        // pinned so that a change of the hash does not go unnoticed
+        assert_eq!(ips[0], ip("10.0.86.21"));
+
+        let other = Hashed::new("ffffffffffffffffffffffffffffffff".parse().unwrap());
+        let mut other =
+            Mapping::with_allocator(Ipv4::from_cidr("10.0.0.0/16").unwrap(), Box::new(other));
+        assert_ne!(other.get_or_insert(&names[0]).unwrap(), ips[0]);
+    }
+
+    #[test]
+    fn hash_collisions_probe_onwards() {
+        let key = "000102030405060708090a0b0c0d0e0f"
+            .parse::<HashKey>()
+            .unwrap();
+        let mut mapping = Mapping::with_allocator(
+            Ipv4::from_cidr("10.0.0.0/29").unwrap(),
+            Box::new(Hashed::new(key)),
+        );
+        let mut ips = (0..6)
+            .map(|i| {
+                let name = Name::from_ascii(format!("{i}.example.com.")).unwrap();
+                mapping.get_or_insert(&name).unwrap()
+            })
+            .collect::<Vec<_>>();
+        ips.sort();
+        ips.dedup();
+        assert_eq!(ips.len(), 6);
+        assert_eq!(mapping.evictions(), 0);
+
+        assert!("00010203".parse::<HashKey>().is_err());
+        assert!(
+            "+0102030405060708090a0b0c0d0e0fx"
+                .parse::<HashKey>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn lru_keeps_each_address_once() {
+        let pool = Ipv4::from_cidr("10.0.0.0/29").unwrap();
+        let mut lru = Lru::default();
+        // released, pinned and removed again, over and over
+        for _ in 0..100 {
+            Allocator::<Ipv4>::release(&mut lru, ip("10.0.0.3"));
+        }
+        Allocator::<Ipv4>::release(&mut lru, ip("10.0.0.1"));
+        assert_eq!(lru.released.len(), 2);
+
+        lru.fresh = pool.capacity();
+        let a = name(0);
+        let context = Context {
+            name: &a,
+            client: None,
+        };
+        let used = |ip: &Ipv4Addr| ip.octets()[3] != 1 && ip.octets()[3] != 3;
+        assert_eq!(
+            lru.allocate(&pool, &used, &context).unwrap(),
+            ip("10.0.0.3")
+        );
+        assert_eq!(
+            lru.allocate(&pool, &used, &context).unwrap(),
+            ip("10.0.0.1")
+        );
+        assert!(lru.released.is_empty() && lru.ticks.is_empty());
+    }
+
+    /// Hands out the address of the client, rejected when outside the pool.
+    struct Echo;
+
+    impl Allocator<Ipv4> for Echo {
+        fn allocate(
+            &mut self,
+            _: &Ipv4,
+            _: &dyn Fn(&Ipv4Addr) -> bool,
+            context: &Context,
+        ) -> Result<Ipv4Addr, MyError> {
+            match context.client.map(|client| client.ip()) {
+                Some(IpAddr::V4(ip)) => Ok(ip),
+                _ => Err(MyError::Mapping("no client".to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn custom_allocator_sees_the_query() {
+        let pool = Ipv4::from_cidr("127.0.0.0/24").unwrap();
+        let mut mapping = Mapping::with_allocator(pool, Box::new(Echo));
+        let (a, b) = (name(0), name(1));
+        let context = Context {
+            name: &a,
+            client: Some(CLIENT),
+        };
+        assert_eq!(mapping.get_or_allocate(&context).unwrap(), ip("127.0.0.1"));
+        assert!(mapping.get_or_insert(&b).is_err());
+        // taken already
+        let context = Context {
+            name: &b,
+            client: Some(CLIENT),
+        };
+        assert!(mapping.get_or_allocate(&context).is_err());
+        assert_eq!(mapping.len(), 1);
+    }
+}
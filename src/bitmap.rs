@@ -0,0 +1,128 @@
+/// Set of used indices below a fixed length, answering "first free index
+/// from here on" in O(log64 n) steps: at most six for the 2^32 addresses of
+/// IPv4, whatever the occupancy.
+///
+/// Level 0 has one bit per index, set when the index is used. Every level
+/// above has one bit per word of the level below, set when that word is
+/// full. Bits past the end are set so they are never handed out. A /8 takes
+/// a little over 2 MiB.
+pub struct Bitmap {
+    levels: Vec<Vec<u64>>,
+}
+
+impl Bitmap {
+    pub fn new(len: u64) -> Self {
+        let mut levels: Vec<Vec<u64>> = Vec::new();
+        let mut bits = len;
+        loop {
+            let words = bits.div_ceil(64).max(1);
+            let mut level = vec![0u64; words as usize];
+            if !bits.is_multiple_of(64) || bits == 0 {
+                level[words as usize - 1] = !0 << (bits % 64);
+            }
+            if let Some(below) = levels.last() {
+                for (i, word) in below.iter().enumerate() {
+                    if *word == !0 {
+                        level[i / 64] |= 1 << (i % 64);
+                    }
+                }
+            }
+            levels.push(level);
+            if words == 1 {
+                break;
+            }
+            bits = words;
+        }
+        Self { levels }
+    }
+
+    pub fn set(&mut self, index: u64, used: bool) {
+        let mut pos = index;
+        for level in &mut self.levels {
+            let word = &mut level[(pos / 64) as usize];
+            let was_full = *word == !0;
+            if used {
+                *word |= 1 << (pos % 64);
+            } else {
+                *word &= !(1 << (pos % 64));
+            }
+            if was_full == (*word == !0) {
+                break;
+            }
+            pos /= 64;
+        }
+    }
+
+    /// First free index from `start` on, wrapping around to 0.
+    pub fn next_free(&self, start: u64) -> Option<u64> {
+        self.search(start)
+            .or_else(|| (start > 0).then(|| self.search(0)).flatten())
+    }
+
+    fn search(&self, start: u64) -> Option<u64> {
+        // climb until a word has a free bit at or after `pos`
+        let mut pos = start;
+        let mut level = 0;
+        loop {
+            let word = *self.levels.get(level)?.get((pos / 64) as usize)?;
+            let free = !word & (!0 << (pos % 64));
+            if free != 0 {
+                pos = pos / 64 * 64 + free.trailing_zeros() as u64;
+                break;
+            }
+            pos = pos / 64 + 1;
+            level += 1;
+        }
+        // a clear bit above means a free bit below
+        while level > 0 {
+            level -= 1;
+            let word = self.levels[level][pos as usize];
+            pos = pos * 64 + (!word).trailing_zeros() as u64;
+        }
+        Some(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitmap;
+
+    #[test]
+    fn next_free_skips_used_and_wraps() {
+        // three levels, the last words partly past the end
+        let len = 64 * 64 * 2 + 100;
+        let mut bitmap = Bitmap::new(len);
+        assert_eq!(bitmap.next_free(0), Some(0));
+        assert_eq!(bitmap.next_free(len - 1), Some(len - 1));
+
+        for i in 10..len - 5 {
+            bitmap.set(i, true);
+        }
+        assert_eq!(bitmap.next_free(10), Some(len - 5));
+        assert_eq!(bitmap.next_free(len - 1), Some(len - 1));
+        bitmap.set(len - 1, true);
+        assert_eq!(bitmap.next_free(len - 1), Some(0));
+
+        bitmap.set(5000, false);
+        assert_eq!(bitmap.next_free(11), Some(5000));
+        for i in (0..10).chain(len - 5..len) {
+            bitmap.set(i, true);
+        }
+        assert_eq!(bitmap.next_free(7), Some(5000));
+        bitmap.set(5000, true);
+        assert_eq!(bitmap.next_free(7), None);
+    }
+
+    #[test]
+    fn small_and_exact_lengths() {
+        assert_eq!(Bitmap::new(0).next_free(0), None);
+        for len in [1, 63, 64, 65, 4096] {
+            let mut bitmap = Bitmap::new(len);
+            for i in 0..len {
+                assert_eq!(bitmap.next_free(0), Some(i), "len {len}");
+                bitmap.set(i, true);
+            }
+            assert_eq!(bitmap.next_free(0), None, "len {len}");
+        }
+    }
+}
@@ -0,0 +1,748 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use hickory_resolver::proto::rr::{Name, RecordType};
+use ipnetwork::Ipv4Network;
+use serde::{Deserialize, Deserializer, de::Error};
+use toml::Spanned;
+
+use crate::{
+    Cli, DEFAULT_POOL, MyError,
+    allocator::{self, AllocationMode, Allocator, Allocators, HashKey},
+    hosts::{HostRecord, Hosts},
+    logger::{Format, Rotate},
+    pool::{Ipv4, Ipv6, Pool},
+    rules::{self, Action, Matcher, Precedence, Rule, Rules},
+};
+
+/// Settings read from the `--config` TOML file, command line flags take
+/// precedence over the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub pool: PoolConfig,
+    /// Named IPv4 pools, drawn from by `fake:<name>` rules.
+    pub pools: BTreeMap<String, NamedPoolConfig>,
+    pub ttl: TtlConfig,
+    pub upstream: UpstreamConfig,
+    pub rules: RulesConfig,
+    pub persist: PersistConfig,
+    pub log: LogConfig,
+    pub admin: AdminConfig,
+    pub hosts: HostsConfig,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// UDP and TCP listen address.
+    pub listen: Option<String>,
+    /// Maximum number of queries answered concurrently.
+    pub concurrency: usize,
+    /// Number of UDP sockets bound with SO_REUSEPORT.
+    pub workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            concurrency: 1024,
+            workers: 1,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Comma separated IPv4 CIDRs.
+    #[serde(deserialize_with = "cidr4")]
+    pub cidr: Option<String>,
+    /// Addresses and CIDRs of `cidr` never handed out.
+    #[serde(deserialize_with = "parsed_list")]
+    pub exclude: Vec<Ipv4Network>,
+    #[serde(deserialize_with = "cidr6")]
+    pub cidr6: Option<String>,
+    /// How every pool, named ones included, picks addresses.
+    pub allocation: AllocationMode,
+    /// Key of `allocation = "hash"`, the same on every replica.
+    #[serde(deserialize_with = "hash_key")]
+    pub hash_key: Option<HashKey>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamedPoolConfig {
+    /// Comma separated IPv4 CIDRs.
+    #[serde(deserialize_with = "cidr4")]
+    pub cidr: Option<String>,
+    /// Addresses and CIDRs of `cidr` never handed out.
+    #[serde(deserialize_with = "parsed_list")]
+    pub exclude: Vec<Ipv4Network>,
+}
+
+/// TTLs of locally built answers, forwarded answers keep the upstream ones.
+/// A rule's `ttl=` takes precedence for the names it matches.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlConfig {
+    /// TTL of fake, static and PTR answers.
+    pub answer: u32,
+    /// TTL of fake answers when it should differ from `answer`. It does not
+    /// shorten the mapping, which lives until evicted.
+    pub fake: Option<u32>,
+    /// TTL of NXDOMAIN and NODATA answers, RFC 2308.
+    pub negative: u32,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        Self {
+            answer: 600,
+            fake: None,
+            negative: 600,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub servers: Vec<SocketAddr>,
+    /// Query types forwarded instead of answered with NODATA.
+    #[serde(deserialize_with = "parsed_list")]
+    pub forward: Vec<RecordType>,
+    /// Domains resolved upstream with their subdomains, ahead of every rule.
+    #[serde(deserialize_with = "domains")]
+    pub exclude: Vec<Name>,
+    /// Resolve unmatched names upstream instead of faking them.
+    pub real_ip: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub precedence: Precedence,
+    /// Rule list files, checked in order before `list`.
+    pub files: Vec<PathBuf>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub list: Vec<Rule>,
+}
+
+/// Fixed records, answered before any rule applies.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostsConfig {
+    /// Files in `/etc/hosts` format.
+    pub files: Vec<PathBuf>,
+    /// `<name> <type> <data>` records, see [`HostRecord`].
+    #[serde(deserialize_with = "parsed_list")]
+    pub records: Vec<HostRecord>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistConfig {
+    /// File the mappings are restored from at startup and saved to.
+    pub file: Option<PathBuf>,
+    /// Seconds between two snapshots.
+    pub interval: u64,
+}
+
+impl Default for PersistConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: Format,
+    /// Log every answered query, not only events.
+    pub queries: bool,
+    /// Append log lines to this file instead of stdout.
+    pub file: Option<PathBuf>,
+    /// Rotate `file` once it would grow past this many bytes, 0 disables.
+    pub max_size: u64,
+    /// Rotate `file` every hour or day.
+    pub rotate: Rotate,
+    /// Number of rotated files kept next to `file`.
+    pub keep: usize,
+    /// Send log lines to the local syslog daemon instead.
+    pub syslog: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: Format::Text,
+            queries: false,
+            file: None,
+            max_size: 0,
+            rotate: Rotate::Never,
+            keep: 5,
+            syslog: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address of the HTTP admin API, disabled when unset.
+    pub listen: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Reads `cli.config` if given and applies the command line on top.
+    pub async fn load(cli: &Cli) -> Result<Self, MyError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::parse(&read(path).await?)
+                .map_err(|e| MyError::Config(format!("{}: {e}", path.display())))?,
+            None => Self::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(document: &str) -> Result<Self, MyError> {
+        toml::from_str(document).map_err(|e| {
+            // toml reports errors inside arrays at the array, list elements
+            // carry their own offset so the error can point at the element
+            match e.message().split_once(ELEMENT_OFFSET) {
+                Some((offset, msg)) => {
+                    let offset = offset.parse().unwrap_or_default();
+                    let line = document[..offset].matches('\n').count() + 1;
+                    let column = offset - document[..offset].rfind('\n').map_or(0, |i| i + 1) + 1;
+                    MyError::Config(format!(
+                        "TOML parse error at line {line}, column {column}\n{msg}"
+                    ))
+                }
+                None => MyError::Config(e.to_string()),
+            }
+        })
+    }
+
+    fn apply(&mut self, cli: &Cli) {
+        fn set<T: Clone>(value: &mut T, flag: &Option<T>) {
+            if let Some(flag) = flag {
+                *value = flag.clone();
+            }
+        }
+        fn set_list<T: Clone>(value: &mut Vec<T>, flag: &[T]) {
+            if !flag.is_empty() {
+                *value = flag.to_vec();
+            }
+        }
+
+        if cli.listen.is_some() {
+            self.server.listen = cli.listen.clone();
+        }
+        set(&mut self.server.concurrency, &cli.concurrency);
+        set(&mut self.server.workers, &cli.workers);
+        if cli.cidr.is_some() {
+            self.pool.cidr = cli.cidr.clone();
+        }
+        set_list(&mut self.pool.exclude, &cli.pool_exclude);
+        set(&mut self.pool.allocation, &cli.allocation);
+        if cli.hash_key.is_some() {
+            self.pool.hash_key = cli.hash_key;
+        }
+        if cli.cidr6.is_some() {
+            self.pool.cidr6 = cli.cidr6.clone();
+        }
+        if !cli.pool.is_empty() {
+            self.pools = cli
+                .pool
+                .iter()
+                .map(|(name, cidr)| {
+                    let pool = NamedPoolConfig {
+                        cidr: Some(cidr.clone()),
+                        exclude: vec![],
+                    };
+                    (name.clone(), pool)
+                })
+                .collect();
+        }
+        set(&mut self.ttl.answer, &cli.ttl);
+        if cli.fake_ttl.is_some() {
+            self.ttl.fake = cli.fake_ttl;
+        }
+        set(&mut self.ttl.negative, &cli.negative_ttl);
+        set_list(&mut self.upstream.servers, &cli.upstream);
+        set_list(&mut self.upstream.forward, &cli.forward);
+        set_list(&mut self.upstream.exclude, &cli.exclude);
+        self.upstream.real_ip |= cli.real_ip;
+        set(&mut self.rules.precedence, &cli.precedence);
+        if let Some(file) = &cli.rule_file {
+            self.rules.files = vec![file.clone()];
+        }
+        set_list(&mut self.rules.list, &cli.rule);
+        set_list(&mut self.hosts.files, &cli.hosts_file);
+        set_list(&mut self.hosts.records, &cli.host);
+        if cli.state.is_some() {
+            self.persist.file = cli.state.clone();
+        }
+        set(&mut self.persist.interval, &cli.snapshot_interval);
+        set(&mut self.log.format, &cli.log_format);
+        self.log.queries |= cli.log_queries;
+        if cli.log_file.is_some() {
+            self.log.file = cli.log_file.clone();
+        }
+        if cli.admin.is_some() {
+            self.admin.listen = cli.admin;
+        }
+    }
+
+    fn validate(&self) -> Result<(), MyError> {
+        if self.pool.cidr.is_none() {
+            return Err(MyError::Config("missing `pool.cidr` or --cidr".to_string()));
+        }
+        if self.pool.allocation == AllocationMode::Hash && self.pool.hash_key.is_none() {
+            return Err(MyError::Config(
+                "hash allocation needs `pool.hash_key` or --hash-key".to_string(),
+            ));
+        }
+        let pool = |name: &str, cidr: &str, exclude: &[Ipv4Network]| {
+            Ipv4::new(cidr, exclude).map_err(|e| MyError::Config(format!("pool `{name}`: {e}")))
+        };
+        let mut pools = vec![(
+            DEFAULT_POOL,
+            pool(
+                DEFAULT_POOL,
+                self.pool.cidr.as_deref().unwrap_or_default(),
+                &self.pool.exclude,
+            )?,
+        )];
+        for (name, config) in &self.pools {
+            let Some(cidr) = &config.cidr else {
+                return Err(MyError::Config(format!("missing `pools.{name}.cidr`")));
+            };
+            if name == DEFAULT_POOL {
+                return Err(MyError::Config(format!(
+                    "the pool name `{DEFAULT_POOL}` is reserved"
+                )));
+            }
+            pools.push((name, pool(name, cidr, &config.exclude)?));
+        }
+        // an address has to lead back to a single pool for PTR lookups
+        for (i, (a, pool_a)) in pools.iter().enumerate() {
+            for (b, pool_b) in &pools[i + 1..] {
+                if pool_a.overlaps(pool_b) {
+                    return Err(MyError::Config(format!("pools `{a}` and `{b}` overlap")));
+                }
+            }
+        }
+        if self.server.listen.is_none() {
+            return Err(MyError::Config(
+                "missing `server.listen` or --listen".to_string(),
+            ));
+        }
+        if self.server.concurrency == 0 || self.server.workers == 0 {
+            return Err(MyError::Config(
+                "`server.concurrency` and `server.workers` must be at least 1".to_string(),
+            ));
+        }
+        if self.persist.interval == 0 {
+            return Err(MyError::Config(
+                "`persist.interval` must be at least 1".to_string(),
+            ));
+        }
+        if self.log.syslog && self.log.file.is_some() {
+            return Err(MyError::Config(
+                "`log.file` and `log.syslog` are exclusive".to_string(),
+            ));
+        }
+        if self.upstream.servers.is_empty()
+            && (!self.upstream.forward.is_empty()
+                || !self.upstream.exclude.is_empty()
+                || self.upstream.real_ip)
+        {
+            return Err(MyError::Config(
+                "forwarding needs `upstream.servers`".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// A fresh allocator of `pool.allocation`, one per pool.
+    pub fn allocator<P: Pool>(&self) -> Box<dyn Allocator<P>> {
+        match (self.pool.allocation, self.pool.hash_key) {
+            (AllocationMode::Sequential, _) => Box::new(allocator::Sequential::default()),
+            (AllocationMode::Hash, Some(key)) => Box::new(allocator::Hashed::new(key)),
+            (AllocationMode::Lru, _) => Box::new(allocator::Lru::default()),
+            _ => Box::new(allocator::Random),
+        }
+    }
+
+    /// Builds the rule set, reading the rule list files.
+    pub async fn rules(&self) -> Result<Rules, MyError> {
+        // ahead of the rules so that under `first` no broader rule overrides
+        // an exclusion
+        let mut rules = self
+            .upstream
+            .exclude
+            .iter()
+            .map(|domain| Rule {
+                matcher: Matcher::Suffix(domain.clone()),
+                action: Action::Forward,
+                ttl: None,
+            })
+            .collect::<Vec<_>>();
+        for path in &self.rules.files {
+            let list = read(path).await?;
+            rules.extend(
+                Rules::parse_list(&list)
+                    .map_err(|e| MyError::Config(format!("{}: {e}", path.display())))?,
+            );
+        }
+        rules.extend(self.rules.list.iter().cloned());
+        for rule in &rules {
+            if let Action::Pool(pool) = &rule.action
+                && !self.pools.contains_key(pool)
+            {
+                return Err(MyError::Config(format!("rule uses unknown pool `{pool}`")));
+            }
+        }
+        let default = if self.upstream.real_ip {
+            Action::Forward
+        } else {
+            Action::Fake
+        };
+        let rules = Rules::new(rules, self.rules.precedence, default);
+        if self.upstream.servers.is_empty() && rules.any(&Action::Forward) {
+            return Err(MyError::Config(
+                "forward rules need `upstream.servers`".to_string(),
+            ));
+        }
+        Ok(rules)
+    }
+
+    /// Builds the hosts table, reading the hosts files.
+    pub async fn hosts(&self) -> Result<Hosts, MyError> {
+        let mut hosts = Hosts::default();
+        for path in &self.hosts.files {
+            hosts
+                .parse_hosts(&read(path).await?)
+                .map_err(|e| MyError::Config(format!("{}: {e}", path.display())))?;
+        }
+        for record in &self.hosts.records {
+            hosts.insert(record.clone())?;
+        }
+        Ok(hosts)
+    }
+}
+
+/// The allocators of `pool.allocation`.
+impl Allocators for Config {
+    fn ipv4(&self, _pool: &str) -> Box<dyn Allocator<Ipv4>> {
+        self.allocator()
+    }
+
+    fn ipv6(&self) -> Box<dyn Allocator<Ipv6>> {
+        self.allocator()
+    }
+}
+
+async fn read(path: &Path) -> Result<String, MyError> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| MyError::Config(format!("{}: {e}", path.display())))
+}
+
+/// Separates the element offset from the message in [`Parsed`] errors.
+const ELEMENT_OFFSET: char = '\u{1f}';
+
+/// String array element parsed with `FromStr`.
+struct Parsed<T>(T);
+
+impl<'de, T> Deserialize<'de> for Parsed<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = Spanned::<String>::deserialize(deserializer)?;
+        s.get_ref()
+            .parse()
+            .map(Parsed)
+            .map_err(|e| D::Error::custom(format!("{}{ELEMENT_OFFSET}{e}", s.span().start)))
+    }
+}
+
+fn parsed_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let list = Vec::<Parsed<T>>::deserialize(deserializer)?;
+    Ok(list.into_iter().map(|parsed| parsed.0).collect())
+}
+
+fn domains<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Name>, D::Error> {
+    let list = Vec::<Spanned<String>>::deserialize(deserializer)?;
+    list.iter()
+        .map(|domain| {
+            rules::fqdn(domain.get_ref()).map_err(|e| {
+                D::Error::custom(format!("{}{ELEMENT_OFFSET}{e}", domain.span().start))
+            })
+        })
+        .collect()
+}
+
+/// Parses a `--pool <name>=<cidr>` flag.
+pub fn named_pool(s: &str) -> Result<(String, String), MyError> {
+    let (name, cidr) = s
+        .split_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| MyError::Config(format!("expected `<name>=<cidr>`, got `{s}`")))?;
+    Ipv4::from_cidr(cidr)
+        .map_err(|e| MyError::Config(format!("invalid IPv4 pool `{cidr}`: {e}")))?;
+    Ok((name.to_string(), cidr.to_string()))
+}
+
+fn cidr4<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let cidr = String::deserialize(deserializer)?;
+    Ipv4::from_cidr(&cidr)
+        .map_err(|e| D::Error::custom(format!("invalid IPv4 pool `{cidr}`: {e}")))?;
+    Ok(Some(cidr))
+}
+
+fn hash_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<HashKey>, D::Error> {
+    let key = String::deserialize(deserializer)?;
+    key.parse().map(Some).map_err(D::Error::custom)
+}
+
+fn cidr6<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let cidr = String::deserialize(deserializer)?;
+    Ipv6::from_cidr(&cidr)
+        .map_err(|e| D::Error::custom(format!("invalid IPv6 pool `{cidr}`: {e}")))?;
+    Ok(Some(cidr))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use hickory_resolver::proto::rr::{Name, RecordType};
+
+    use super::{Config, Format, Rotate};
+    use crate::{
+        Cli,
+        allocator::AllocationMode,
+        rules::{Action, Precedence, Rules},
+    };
+
+    const FULL: &str = r#"
+[server]
+listen = "127.0.0.1:5353"
+concurrency = 64
+workers = 2
+
+[pool]
+cidr = "198.18.0.0/15"
+exclude = ["198.18.0.1", "198.18.255.240/28"]
+cidr6 = "fd00::/64"
+allocation = "hash"
+hash_key = "000102030405060708090a0b0c0d0e0f"
+
+[pools.tenant-a]
+cidr = "100.64.0.0/16"
+
+[pools.tenant-b]
+cidr = "100.65.0.0/16, 100.66.0.0/16"
+
+[ttl]
+answer = 60
+fake = 1
+negative = 30
+
+[upstream]
+servers = ["1.1.1.1:53", "[2606:4700:4700::1111]:53"]
+forward = ["MX", "TXT"]
+exclude = ["lan"]
+real_ip = false
+
+[rules]
+precedence = "specific"
+files = []
+list = ["suffix:example.com forward", "keyword:ads block", "suffix:corp.lan fake:tenant-a"]
+
+[persist]
+file = "/var/lib/fake-dns/state"
+interval = 30
+
+[log]
+format = "json"
+queries = true
+file = "/var/log/fake-dns.log"
+max_size = 10485760
+rotate = "daily"
+keep = 3
+
+[hosts]
+files = ["/etc/hosts"]
+records = ["nas.lan A 192.168.1.10", "lan MX 10 mail.lan"]
+"#;
+
+    #[test]
+    fn full_schema() {
+        let config = Config::parse(FULL).unwrap();
+        assert_eq!(config.server.workers, 2);
+        assert_eq!(config.pool.cidr6.as_deref(), Some("fd00::/64"));
+        assert_eq!(config.ttl.answer, 60);
+        assert_eq!(config.ttl.fake, Some(1));
+        assert_eq!(config.ttl.negative, 30);
+        assert_eq!(
+            config.upstream.forward,
+            vec![RecordType::MX, RecordType::TXT]
+        );
+        assert_eq!(
+            config.upstream.exclude,
+            vec![Name::from_ascii("lan.").unwrap()]
+        );
+        assert_eq!(config.rules.precedence, Precedence::Specific);
+        assert_eq!(config.pool.exclude.len(), 2);
+        assert_eq!(config.pool.allocation, AllocationMode::Hash);
+        assert_eq!(
+            config.pools["tenant-b"].cidr.as_deref(),
+            Some("100.65.0.0/16, 100.66.0.0/16")
+        );
+        assert_eq!(config.rules.list.len(), 3);
+        assert_eq!(config.persist.interval, 30);
+        assert_eq!(config.log.format, Format::Json);
+        assert_eq!(config.log.rotate, Rotate::Daily);
+        assert_eq!(config.log.keep, 3);
+        assert_eq!(config.hosts.records.len(), 2);
+    }
+
+    #[test]
+    fn precise_errors() {
+        let e = Config::parse("[server]\nlisten = \"127.0.0.1:53\"\nthreads = 4\n").unwrap_err();
+        assert!(e.to_string().contains("line 3, column 1"), "{e}");
+        assert!(e.to_string().contains("unknown field `threads`"), "{e}");
+
+        let e = Config::parse("[pool]\ncidr = \"10.0.0.0/33\"\n").unwrap_err();
+        assert!(e.to_string().contains("line 2, column 8"), "{e}");
+        assert!(e.to_string().contains("not an IPv4 CIDR"), "{e}");
+
+        let e = Config::parse("[pool]\ncidr = \"10.0.0.0/31\"\n").unwrap_err();
+        assert!(e.to_string().contains("/30 or shorter"), "{e}");
+        let e = super::named_pool("a=10.1.0.0/40").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid IPv4 pool `10.1.0.0/40`: not an IPv4 CIDR"
+        );
+
+        let e = Config::parse("[pool]\nexclude = [\"10.0.0.1\", \"10.0.0.0/40\"]\n").unwrap_err();
+        assert!(e.to_string().contains("line 2, column 24"), "{e}");
+
+        let e = Config::parse("[upstream]\nexclude = [\"lan\", \"a..b\"]\n").unwrap_err();
+        assert!(e.to_string().contains("line 2, column 19"), "{e}");
+
+        let e = Config::parse(
+            "[rules]\nlist = [\n  \"exact:a.com fake\",\n  \"exact:b.com nope\",\n]\n",
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("line 4, column 3"), "{e}");
+        assert!(e.to_string().contains("unknown action `nope`"), "{e}");
+    }
+
+    #[test]
+    fn example_config() {
+        Config::parse(include_str!("../config.example.toml")).unwrap();
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let cli = Cli::parse_from(["fake-dns", "--listen", "0.0.0.0:53", "--ttl", "5"]);
+        let mut config = Config::parse(FULL).unwrap();
+        config.apply(&cli);
+        assert_eq!(config.server.listen.as_deref(), Some("0.0.0.0:53"));
+        assert_eq!(config.ttl.answer, 5);
+        assert_eq!(config.pool.cidr.as_deref(), Some("198.18.0.0/15"));
+        config.validate().unwrap();
+
+        config.apply(&Cli::parse_from(["fake-dns", "--snapshot-interval", "0"]));
+        assert!(config.validate().is_err());
+        config.persist.interval = 60;
+
+        let cli = Cli::parse_from(["fake-dns", "--allocation", "lru"]);
+        config.apply(&cli);
+        assert_eq!(config.pool.allocation, AllocationMode::Lru);
+        config.pool.hash_key = None;
+        config.apply(&Cli::parse_from(["fake-dns", "--allocation", "hash"]));
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn excluded_domains_and_real_ip_forward() {
+        let mut config = Config::parse(
+            "[upstream]\nservers = [\"127.0.0.1:53\"]\nexclude = [\"Example.com\"]\n\
+             [rules]\nlist = [\"keyword:example block\"]\n",
+        )
+        .unwrap();
+        let action =
+            |rules: &Rules, name: &str| rules.action(&Name::from_ascii(name).unwrap()).0.clone();
+
+        let rules = config.rules().await.unwrap();
+        for name in ["example.com.", "www.example.COM.", "a.b.example.com."] {
+            assert_eq!(action(&rules, name), Action::Forward, "{name}");
+        }
+        // the broader rule only catches what the exclusion leaves
+        for name in ["example.org.", "notexample.com."] {
+            assert_eq!(action(&rules, name), Action::Block, "{name}");
+        }
+        assert_eq!(action(&rules, "com."), Action::Fake);
+
+        config.upstream.real_ip = true;
+        let rules = config.rules().await.unwrap();
+        assert_eq!(action(&rules, "other.org."), Action::Forward);
+
+        config.upstream.servers.clear();
+        assert!(config.rules().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn named_pools() {
+        let cli = Cli::parse_from([
+            "fake-dns",
+            "--pool",
+            "a=100.64.0.0/16",
+            "--pool",
+            "b=100.64.128.0/20",
+        ]);
+        let mut config = Config::parse(FULL).unwrap();
+        config.apply(&cli);
+        let e = config.validate().unwrap_err();
+        assert!(e.to_string().contains("pools `a` and `b` overlap"), "{e}");
+
+        // unless `a` leaves the addresses of `b` out
+        let exclude = "100.64.128.0/20".parse().unwrap();
+        config.pools.get_mut("a").unwrap().exclude.push(exclude);
+        config.validate().unwrap();
+        config.pools.get_mut("a").unwrap().exclude.clear();
+        config.pools.remove("b");
+        config.validate().unwrap();
+        // the rules of FULL draw from `tenant-a`, which --pool replaced
+        let Err(e) = config.rules().await else {
+            panic!("expected an unknown pool");
+        };
+        assert!(e.to_string().contains("unknown pool `tenant-a`"), "{e}");
+
+        assert!(Cli::try_parse_from(["fake-dns", "--pool", "a=10.0.0.0/33"]).is_err());
+        assert!(Cli::try_parse_from(["fake-dns", "--pool", "=10.0.0.0/8"]).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use hickory_resolver::proto::rr::{
+    Name, RData, Record, RecordType,
+    rdata::{CNAME, MX, SRV, TXT},
+};
+
+use crate::{MyError, rules};
+
+/// Longest CNAME chain followed inside the hosts table.
+const MAX_CHAIN: usize = 8;
+
+/// A `<name> <type> <data>` record, e.g. `mail.lan MX 10 mx.lan`.
+///
+/// Supported types are A, AAAA, CNAME, TXT, MX (`<preference> <exchange>`)
+/// and SRV (`<priority> <weight> <port> <target>`).
+#[derive(Debug, Clone)]
+pub struct HostRecord {
+    pub name: Name,
+    pub rdata: RData,
+}
+
+impl FromStr for HostRecord {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MyError::Config(format!("invalid record `{s}`"));
+        let mut fields = s.trim().splitn(3, char::is_whitespace);
+        let (Some(name), Some(kind), Some(data)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid());
+        };
+        let data = data.trim();
+        let numbers = |count: usize| -> Result<(Vec<u16>, Name), MyError> {
+            let fields = data.split_whitespace().collect::<Vec<_>>();
+            let [numbers @ .., target] = &fields[..] else {
+                return Err(invalid());
+            };
+            if numbers.len() != count {
+                return Err(invalid());
+            }
+            let numbers = numbers
+                .iter()
+                .map(|n| n.parse().or(Err(invalid())))
+                .collect::<Result<_, _>>()?;
+            Ok((numbers, rules::fqdn(target)?))
+        };
+
+        let rdata = match kind.to_ascii_uppercase().as_str() {
+            "A" => RData::A(data.parse().or(Err(invalid()))?),
+            "AAAA" => RData::AAAA(data.parse().or(Err(invalid()))?),
+            "CNAME" => RData::CNAME(CNAME(rules::fqdn(data)?)),
+            "TXT" => {
+                let text = data
+                    .strip_prefix('"')
+                    .and_then(|data| data.strip_suffix('"'))
+                    .unwrap_or(data);
+                RData::TXT(TXT::new(vec![text.to_string()]))
+            }
+            "MX" => {
+                let (numbers, exchange) = numbers(1)?;
+                RData::MX(MX::new(numbers[0], exchange))
+            }
+            "SRV" => {
+                let (numbers, target) = numbers(3)?;
+                RData::SRV(SRV::new(numbers[0], numbers[1], numbers[2], target))
+            }
+            _ => return Err(MyError::Config(format!("unsupported record type `{kind}`"))),
+        };
+
+        Ok(Self {
+            name: rules::fqdn(name)?,
+            rdata,
+        })
+    }
+}
+
+/// Fixed records answered instead of fake addresses.
+#[derive(Default)]
+pub struct Hosts {
+    names: HashMap<Name, Vec<RData>>,
+}
+
+impl Hosts {
+    pub fn insert(&mut self, record: HostRecord) -> Result<(), MyError> {
+        let data = self.names.entry(record.name.clone()).or_default();
+        if data.contains(&record.rdata) {
+            return Ok(());
+        }
+        // RFC 1034 section 3.6.2, a CNAME owner has no other data
+        let cname = |rdata: &RData| rdata.record_type() == RecordType::CNAME;
+        if !data.is_empty() && (cname(&record.rdata) || data.iter().any(cname)) {
+            return Err(MyError::Config(format!(
+                "{} has a CNAME besides other records",
+                record.name
+            )));
+        }
+        data.push(record.rdata);
+        Ok(())
+    }
+
+    /// Adds the entries of a hosts file, `<ip> <name> [<alias>...]` per line
+    /// and `#` starting a comment.
+    pub fn parse_hosts(&mut self, text: &str) -> Result<(), MyError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let error = |msg: String| MyError::Config(format!("line {}: {msg}", i + 1));
+            let rdata = match ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => RData::A(ip.into()),
+                Ok(IpAddr::V6(ip)) => RData::AAAA(ip.into()),
+                Err(_) => return Err(error(format!("invalid address `{ip}`"))),
+            };
+            let mut names = fields.peekable();
+            if names.peek().is_none() {
+                return Err(error(format!("no name for `{ip}`")));
+            }
+            for name in names {
+                let name = rules::fqdn(name).map_err(|e| error(e.to_string()))?;
+                self.insert(HostRecord {
+                    name,
+                    rdata: rdata.clone(),
+                })
+                .map_err(|e| error(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer section for `name`, following CNAMEs within the table, or
+    /// `None` when the table knows nothing about `name`. An empty answer
+    /// means NODATA.
+    pub fn answer(&self, name: &Name, query_type: RecordType, ttl: u32) -> Option<Vec<Record>> {
+        let mut answers = Vec::new();
+        let mut owner = name.clone();
+        let mut data = self.names.get(name)?;
+
+        for _ in 0..MAX_CHAIN {
+            let matching = data
+                .iter()
+                .filter(|rdata| rdata.record_type() == query_type)
+                .map(|rdata| Record::from_rdata(owner.clone(), ttl, rdata.clone()))
+                .collect::<Vec<_>>();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+            let Some(RData::CNAME(target)) = data.first() else {
+                break;
+            };
+            answers.push(Record::from_rdata(owner, ttl, RData::CNAME(target.clone())));
+            owner = target.0.clone();
+            match self.names.get(&owner) {
+                Some(next) => data = next,
+                None => break,
+            }
+        }
+        Some(answers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::rr::{Name, RData, RecordType};
+
+    use super::{HostRecord, Hosts};
+
+    fn hosts() -> Hosts {
+        let mut hosts = Hosts::default();
+        for record in [
+            "nas.lan A 192.168.1.10",
+            "nas.lan AAAA fd00::10",
+            "files.lan CNAME nas.lan",
+            "lan TXT \"v=spf1 -all\"",
+            "lan MX 10 mail.lan",
+            "_sip._tcp.lan SRV 10 5 5060 sip.lan",
+        ] {
+            hosts.insert(record.parse().unwrap()).unwrap();
+        }
+        hosts
+            .parse_hosts("# comment\n192.168.1.1 router.lan gw.lan # trailing\n\n::1 localhost\n")
+            .unwrap();
+        hosts
+    }
+
+    fn answer(hosts: &Hosts, name: &str, query_type: RecordType) -> Option<Vec<RData>> {
+        let name = Name::from_ascii(name).unwrap();
+        let answers = hosts.answer(&name, query_type, 60)?;
+        Some(answers.iter().map(|record| record.data().clone()).collect())
+    }
+
+    #[test]
+    fn records_and_hosts_file() {
+        let hosts = hosts();
+        assert_eq!(
+            answer(&hosts, "NAS.lan.", RecordType::A),
+            Some(vec![RData::A("192.168.1.10".parse().unwrap())])
+        );
+        assert_eq!(
+            answer(&hosts, "gw.lan.", RecordType::A),
+            Some(vec![RData::A("192.168.1.1".parse().unwrap())])
+        );
+        assert_eq!(answer(&hosts, "lan.", RecordType::TXT).unwrap().len(), 1);
+        assert_eq!(answer(&hosts, "lan.", RecordType::MX).unwrap().len(), 1);
+        assert_eq!(
+            answer(&hosts, "_sip._tcp.lan.", RecordType::SRV)
+                .unwrap()
+                .len(),
+            1
+        );
+        // known name, other type
+        assert_eq!(
+            answer(&hosts, "router.lan.", RecordType::AAAA),
+            Some(vec![])
+        );
+        assert_eq!(answer(&hosts, "example.com.", RecordType::A), None);
+
+        let chain = answer(&hosts, "files.lan.", RecordType::AAAA).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].record_type(), RecordType::CNAME);
+        assert_eq!(chain[1], RData::AAAA("fd00::10".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_records() {
+        for record in [
+            "nas.lan",
+            "nas.lan A 300.1.1.1",
+            "nas.lan MX mail.lan",
+            "nas.lan SRV 1 2 mail.lan",
+            "nas.lan PTR a.lan",
+        ] {
+            assert!(record.parse::<HostRecord>().is_err(), "{record}");
+        }
+
+        let mut hosts = hosts();
+        assert!(
+            hosts
+                .insert("nas.lan CNAME other.lan".parse().unwrap())
+                .is_err()
+        );
+        assert!(
+            hosts
+                .insert("files.lan A 10.0.0.1".parse().unwrap())
+                .is_err()
+        );
+        let e = hosts
+            .parse_hosts("10.0.0.1 a.lan\nnot-an-ip b.lan")
+            .unwrap_err();
+        assert!(e.to_string().contains("line 2"), "{e}");
+    }
+}
@@ -0,0 +1,1241 @@
+//! Fake IP DNS server. The binary only calls [`run`], [`run_with`] serves
+//! with allocators of other strategies, built on the public fake address
+//! pools, allocators and mapping tables.
+
+use std::{
+    collections::BTreeMap,
+    error::{self, Error},
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use allocator::{AllocationMode, Allocators, Context, HashKey};
+use clap::Parser;
+use config::{Config, TtlConfig};
+use hickory_resolver::proto::{
+    op::{Edns, Header, Message, MessageType, OpCode, ResponseCode},
+    rr::{
+        DNSClass, Name, RData, Record, RecordType,
+        rdata::{PTR, SOA},
+    },
+    serialize::binary::{BinDecodable, BinDecoder, BinEncodable},
+};
+use hosts::{HostRecord, Hosts};
+use ipnetwork::Ipv4Network;
+use logger::{Format, QueryLog};
+use mapping::Mapping;
+use metrics::Metrics;
+use pool::{Ipv4, Ipv6};
+use rules::{Action, Precedence, Rule, Rules};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::Semaphore,
+    task::JoinSet,
+};
+use upstream::Upstream;
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logger::message($crate::logger::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logger::message($crate::logger::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+mod admin;
+pub mod allocator;
+mod bitmap;
+mod config;
+mod hosts;
+mod logger;
+pub mod mapping;
+mod metrics;
+mod persist;
+pub mod pool;
+mod reload;
+mod rules;
+mod tcp;
+mod udp;
+mod upstream;
+
+/// Parses the command line and serves until a shutdown signal.
+pub async fn run() -> Result<(), Box<dyn error::Error>> {
+    serve(None).await
+}
+
+/// Like [`run`], with every pool allocating through `allocators` instead of
+/// the configured `pool.allocation`.
+pub async fn run_with(allocators: impl Allocators) -> Result<(), Box<dyn error::Error>> {
+    serve(Some(&allocators)).await
+}
+
+async fn serve(allocators: Option<&dyn Allocators>) -> Result<(), Box<dyn error::Error>> {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    logger::init(&config.log)?;
+
+    let server = Arc::new(Server::new(&config, allocators.unwrap_or(&config)).await?);
+
+    let state = config.persist.file.clone();
+    if let Some(state) = &state {
+        persist::load(&server, state).await?;
+    }
+    let snapshots = state.clone().map(|state| {
+        let interval = Duration::from_secs(config.persist.interval);
+        tokio::spawn(persist::run(server.clone(), state, interval))
+    });
+
+    let listen = config.server.listen.as_deref().unwrap_or_default();
+    info!("start listening on {}", listen);
+
+    let listener = TcpListener::bind(listen).await?;
+    tokio::spawn(tcp::serve(listener, server.clone()));
+
+    if let Some(admin) = config.admin.listen {
+        info!("admin api listening on {}", admin);
+        let listener = TcpListener::bind(admin).await?;
+        tokio::spawn(admin::serve(listener, server.clone()));
+    }
+
+    let mut workers = JoinSet::new();
+    if config.server.workers > 1 {
+        let addr = tokio::net::lookup_host(listen)
+            .await?
+            .next()
+            .ok_or(MyError::Listen)?;
+        for _ in 0..config.server.workers {
+            let socket = Arc::new(udp::bind_reuse_port(addr)?);
+            workers.spawn(udp::serve(socket, server.clone()));
+        }
+    } else {
+        let socket = Arc::new(UdpSocket::bind(listen).await?);
+        workers.spawn(udp::serve(socket, server.clone()));
+    }
+
+    tokio::spawn(reload::run(server.clone(), cli, config));
+
+    let result = tokio::select! {
+        result = async {
+            while let Some(worker) = workers.join_next().await {
+                worker??;
+            }
+            Ok::<_, Box<dyn error::Error>>(())
+        } => result,
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            Ok(())
+        }
+    };
+
+    if let (Some(snapshots), Some(state)) = (snapshots, &state) {
+        snapshots.abort();
+        persist::save(&server, state).await?;
+    }
+    result
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Payload size advertised in our OPT records, see DNS flag day 2020.
+const UDP_PAYLOAD: u16 = 1232;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Wire format answer to `data`, `None` when there is nothing to send back.
+///
+/// UDP answers larger than the client's advertised payload size are sent
+/// with empty sections and the TC bit set so the client retries over TCP.
+async fn respond(
+    data: &[u8],
+    server: &Server,
+    client: SocketAddr,
+    transport: Transport,
+) -> Option<Vec<u8>> {
+    let start = Instant::now();
+    let metrics = &server.metrics;
+    let request = match Message::from_bytes(data) {
+        Ok(request) => request,
+        Err(e) => {
+            metrics.parse_failures.inc("proto");
+            warn!("failed to parse request bytes {:?}", e);
+            // answer FORMERR as long as the header can be read
+            let header = Header::read(&mut BinDecoder::new(data)).ok()?;
+            if header.message_type() == MessageType::Response {
+                return None;
+            }
+            let mut response = Message::new();
+            response.set_id(header.id());
+            response.set_message_type(MessageType::Response);
+            response.set_op_code(header.op_code());
+            response.set_recursion_desired(header.recursion_desired());
+            response.set_response_code(ResponseCode::FormErr);
+            metrics.queries_by_rcode.inc("FormErr");
+            return response.to_bytes().ok();
+        }
+    };
+    // never answer answers, two servers could ping-pong forever
+    if request.message_type() == MessageType::Response {
+        return None;
+    }
+
+    let (mut response, action) = match query(&request, server, client).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            if let MyError::EmptyQuery = e {
+                metrics.parse_failures.inc("empty_query");
+            }
+            warn!("failed to answer request {}: {:?}", request.id(), e);
+            (error_response(&request, e.rcode()), "none")
+        }
+    };
+    metrics
+        .queries_by_rcode
+        .inc(format!("{:?}", response.response_code()));
+    metrics.queries_by_action.inc(action);
+    if let Some(query) = request.queries().first() {
+        metrics.queries_by_type.inc(query.query_type());
+        logger::query(&QueryLog {
+            client,
+            qname: query.name(),
+            qtype: query.query_type(),
+            action,
+            answers: response.answers(),
+            rcode: response.response_code(),
+            latency: start.elapsed(),
+        });
+    }
+
+    let limit = match transport {
+        // never more than we advertise, larger datagrams risk fragmentation
+        Transport::Udp => request.max_payload().min(UDP_PAYLOAD) as usize,
+        Transport::Tcp => u16::MAX as usize,
+    };
+
+    let bytes = match response.to_bytes() {
+        Ok(b) if b.len() > limit => {
+            response.take_answers();
+            response.take_name_servers();
+            response.take_additionals();
+            response.set_truncated(true);
+            response.to_bytes()
+        }
+        b => b,
+    };
+
+    metrics.latency.observe(start.elapsed());
+    match bytes {
+        Ok(b) => Some(b),
+        Err(e) => {
+            warn!("failed to parse message: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Name of the `[pool]` pools in metrics and the admin API.
+const DEFAULT_POOL: &str = "default";
+
+struct Server {
+    mapping: Mutex<Mapping<Ipv4>>,
+    mapping6: Option<Mutex<Mapping<Ipv6>>>,
+    /// Named IPv4 pools, they never overlap each other or `mapping`.
+    pools: BTreeMap<String, Mutex<Mapping<Ipv4>>>,
+    /// Swapped as a whole on reload, see [`reload`].
+    settings: RwLock<Arc<Settings>>,
+    /// Bounds the number of queries answered at the same time.
+    concurrency: Arc<Semaphore>,
+    metrics: Metrics,
+}
+
+/// The part of the configuration that can change without a restart.
+struct Settings {
+    upstream: Option<Upstream>,
+    /// Query types relayed upstream instead of answered with NODATA.
+    forward: Vec<RecordType>,
+    rules: Rules,
+    hosts: Hosts,
+    ttl: TtlConfig,
+}
+
+impl Settings {
+    async fn new(config: &Config) -> Result<Self, MyError> {
+        let servers = &config.upstream.servers;
+        Ok(Self {
+            upstream: (!servers.is_empty()).then(|| Upstream::new(servers)),
+            forward: config.upstream.forward.clone(),
+            rules: config.rules().await?,
+            hosts: config.hosts().await?,
+            ttl: config.ttl,
+        })
+    }
+}
+
+/// Empty response with the id, opcode, RD and CD bits of `request`.
+fn reply_to(request: &Message) -> Message {
+    let mut response = Message::new();
+    response.set_id(request.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(request.op_code());
+    response.set_recursion_desired(request.recursion_desired());
+    response.set_checking_disabled(request.checking_disabled());
+    response
+}
+
+/// Response carrying nothing but `rcode` for a request `query` failed on.
+fn error_response(request: &Message, rcode: ResponseCode) -> Message {
+    let mut response = reply_to(request);
+    response.set_response_code(rcode);
+    if let [query] = request.queries() {
+        response.add_query(query.clone());
+    }
+    response
+}
+
+/// Answers `request`, together with the name of the rule action behind the
+/// answer: `ptr` for reverse lookups of fake addresses, `hosts` for fixed
+/// records and `none` when the request was rejected before the rules were
+/// consulted.
+async fn query(
+    request: &Message,
+    server: &Server,
+    client: SocketAddr,
+) -> Result<(Message, &'static str), MyError> {
+    if request.op_code() != OpCode::Query {
+        return Err(MyError::NotImplemented);
+    }
+    let query = request.queries().first().ok_or(MyError::EmptyQuery)?;
+    // only the internet class is served and there is no zone to transfer
+    if query.query_class() != DNSClass::IN
+        || matches!(query.query_type(), RecordType::AXFR | RecordType::IXFR)
+    {
+        return Err(MyError::Refused);
+    }
+    let settings = server.settings();
+
+    // the question is echoed as received, 0x20 case randomisation included;
+    // everything not relayed from upstream is our own authoritative answer
+    let mut response = reply_to(request);
+    response.set_response_code(ResponseCode::NoError);
+    response.set_authoritative(true);
+    response.set_recursion_available(settings.upstream.is_some());
+    response.add_query(query.clone());
+
+    if let Some(edns) = request.extensions() {
+        let mut opt = Edns::new();
+        opt.set_max_payload(UDP_PAYLOAD);
+        opt.set_dnssec_ok(edns.flags().dnssec_ok);
+        response.set_edns(opt);
+
+        if edns.version() > 0 {
+            response.set_response_code(ResponseCode::BADVERS);
+            return Ok((response, "none"));
+        }
+    }
+
+    if query.query_type() == RecordType::PTR
+        && let Some(ip) = arpa_ip(query.name())
+        && let Some(domain) = server.domain(ip)
+    {
+        match domain {
+            Some(domain) => {
+                let rdata = RData::PTR(PTR(domain));
+                response.add_answer(Record::from_rdata(
+                    query.name().clone(),
+                    settings.ttl.answer,
+                    rdata,
+                ));
+            }
+            None => {
+                response.set_response_code(ResponseCode::NXDomain);
+                response.add_name_server(soa(query.name(), settings.ttl.negative));
+            }
+        }
+        return Ok((response, "ptr"));
+    }
+
+    if let Some(answers) =
+        settings
+            .hosts
+            .answer(query.name(), query.query_type(), settings.ttl.answer)
+    {
+        if answers.is_empty() {
+            response.add_name_server(soa(query.name(), settings.ttl.negative));
+        }
+        response.add_answers(answers);
+        return Ok((response, "hosts"));
+    }
+
+    let (action, rule_ttl) = settings.rules.action(query.name());
+    let ttl = rule_ttl.unwrap_or(settings.ttl.answer);
+    let negative_ttl = settings.ttl.negative;
+    let pool = match action {
+        Action::Fake => None,
+        Action::Pool(pool) => Some(pool.as_str()),
+        Action::Forward => {
+            match &settings.upstream {
+                Some(upstream) => upstream.forward(query, &mut response).await,
+                None => {
+                    response.set_response_code(ResponseCode::ServFail);
+                }
+            }
+            return Ok((response, action.name()));
+        }
+        Action::Block => {
+            response.set_response_code(ResponseCode::NXDomain);
+            response.add_name_server(soa(query.name(), rule_ttl.unwrap_or(negative_ttl)));
+            return Ok((response, action.name()));
+        }
+        Action::Static(ips) => {
+            let answers = ips.iter().filter_map(|ip| match (query.query_type(), ip) {
+                (RecordType::A, IpAddr::V4(ip)) => Some(RData::A((*ip).into())),
+                (RecordType::AAAA, IpAddr::V6(ip)) => Some(RData::AAAA((*ip).into())),
+                _ => None,
+            });
+            response.add_answers(
+                answers.map(|rdata| Record::from_rdata(query.name().clone(), ttl, rdata)),
+            );
+            if response.answers().is_empty() {
+                response.add_name_server(soa(query.name(), negative_ttl));
+            }
+            return Ok((response, action.name()));
+        }
+    };
+
+    let fake_ttl = rule_ttl
+        .or(settings.ttl.fake)
+        .unwrap_or(settings.ttl.answer);
+    let context = Context {
+        name: query.name(),
+        client: Some(client),
+    };
+    match query.query_type() {
+        RecordType::A => {
+            let ip = server
+                .pool(pool)?
+                .lock()
+                .unwrap()
+                .get_or_allocate(&context)?;
+            let record = Record::from_rdata(query.name().clone(), fake_ttl, RData::A(ip.into()));
+            response.add_answer(record);
+        }
+        // named pools are IPv4 only
+        RecordType::AAAA if pool.is_none() && server.mapping6.is_some() => {
+            let mapping6 = server.mapping6.as_ref().unwrap();
+            let ip = mapping6.lock().unwrap().get_or_allocate(&context)?;
+            let record = Record::from_rdata(query.name().clone(), fake_ttl, RData::AAAA(ip.into()));
+            response.add_answer(record);
+        }
+        query_type => match &settings.upstream {
+            Some(upstream) if settings.forward.contains(&query_type) => {
+                upstream.forward(query, &mut response).await;
+            }
+            _ => {
+                response.add_name_server(soa(query.name(), negative_ttl));
+            }
+        },
+    }
+    Ok((response, action.name()))
+}
+
+impl Server {
+    async fn new(config: &Config, allocators: &dyn Allocators) -> Result<Self, MyError> {
+        Ok(Self {
+            mapping: Mutex::new(Mapping::with_allocator(
+                Ipv4::new(
+                    config.pool.cidr.as_deref().unwrap_or_default(),
+                    &config.pool.exclude,
+                )?,
+                allocators.ipv4(DEFAULT_POOL),
+            )),
+            mapping6: match &config.pool.cidr6 {
+                Some(cidr6) => Some(Mutex::new(Mapping::with_allocator(
+                    Ipv6::from_cidr(cidr6)?,
+                    allocators.ipv6(),
+                ))),
+                None => None,
+            },
+            pools: config
+                .pools
+                .iter()
+                .map(|(name, pool)| {
+                    let cidr = pool.cidr.as_deref().unwrap_or_default();
+                    Ok((
+                        name.clone(),
+                        Mutex::new(Mapping::with_allocator(
+                            Ipv4::new(cidr, &pool.exclude)?,
+                            allocators.ipv4(name),
+                        )),
+                    ))
+                })
+                .collect::<Result<_, MyError>>()?,
+            settings: RwLock::new(Arc::new(Settings::new(config).await?)),
+            concurrency: Arc::new(Semaphore::new(config.server.concurrency)),
+            metrics: Metrics::default(),
+        })
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// The named IPv4 pool, or the default one for `None`.
+    fn pool(&self, name: Option<&str>) -> Result<&Mutex<Mapping<Ipv4>>, MyError> {
+        match name {
+            None => Ok(&self.mapping),
+            Some(name) => self.pools.get(name).ok_or_else(|| {
+                MyError::Mapping(format!("pool `{name}` is not running, restart to add it"))
+            }),
+        }
+    }
+
+    /// Every IPv4 pool by name, the default one first.
+    fn mappings(&self) -> impl Iterator<Item = (&str, &Mutex<Mapping<Ipv4>>)> {
+        std::iter::once((DEFAULT_POOL, &self.mapping))
+            .chain(self.pools.iter().map(|(name, pool)| (name.as_str(), pool)))
+    }
+
+    /// Domain mapped to `ip`, `None` when `ip` lies outside every fake pool.
+    fn domain(&self, ip: IpAddr) -> Option<Option<Name>> {
+        match ip {
+            IpAddr::V4(ip) => self.mappings().find_map(|(_, mapping)| {
+                let mapping = mapping.lock().unwrap();
+                mapping.contains(ip).then(|| mapping.domain(ip).cloned())
+            }),
+            IpAddr::V6(ip) => {
+                let mapping6 = self.mapping6.as_ref()?.lock().unwrap();
+                mapping6.contains(ip).then(|| mapping6.domain(ip).cloned())
+            }
+        }
+    }
+}
+
+/// SOA placed in the authority section of negative answers, `ttl` is both
+/// its own TTL and the minimum resolvers cache the answer for.
+fn soa(name: &Name, ttl: u32) -> Record {
+    let soa = SOA::new(
+        Name::from_ascii("fake-dns.").unwrap(),
+        Name::from_ascii("hostmaster.fake-dns.").unwrap(),
+        1,
+        3600,
+        600,
+        86400,
+        ttl,
+    );
+    Record::from_rdata(name.clone(), ttl, RData::SOA(soa))
+}
+
+/// Address behind a full `in-addr.arpa` / `ip6.arpa` name.
+fn arpa_ip(name: &Name) -> Option<IpAddr> {
+    let net = name.parse_arpa_name().ok()?;
+    (net.prefix_len() == net.max_prefix_len()).then(|| net.addr())
+}
+
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about, long_about=None)]
+struct Cli {
+    /// TOML configuration file, flags below override its values
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// IPv4 pool, e.g. `198.18.0.0/15` or a comma separated list of CIDRs
+    #[arg(long, short)]
+    cidr: Option<String>,
+    /// Address or CIDR of the IPv4 pool never handed out, may be repeated
+    #[arg(long, value_delimiter = ',')]
+    pool_exclude: Vec<Ipv4Network>,
+    /// How new names pick their address, `hash` lets replicas agree and `lru`
+    /// reuses released addresses last [default: random]
+    #[arg(long, value_enum)]
+    allocation: Option<AllocationMode>,
+    /// Key of hash allocation as 32 hex digits, the same on every replica
+    #[arg(long)]
+    hash_key: Option<HashKey>,
+    /// Optional IPv6 pool for AAAA answers, e.g. a ULA /64
+    #[arg(long)]
+    cidr6: Option<String>,
+    /// Named IPv4 pool for `fake:<name>` rules, e.g. `tenant-a=100.64.0.0/16`, may be repeated
+    #[arg(long, value_parser = config::named_pool)]
+    pool: Vec<(String, String)>,
+    #[arg(long, short)]
+    listen: Option<String>,
+    /// TTL of fake, static and PTR answers in seconds [default: 600]
+    #[arg(long)]
+    ttl: Option<u32>,
+    /// TTL of fake answers only, e.g. 1 to keep clients asking [default: --ttl]
+    #[arg(long)]
+    fake_ttl: Option<u32>,
+    /// TTL of NXDOMAIN and NODATA answers in seconds [default: 600]
+    #[arg(long)]
+    negative_ttl: Option<u32>,
+    /// Upstream dns server, may be repeated
+    #[arg(long, short)]
+    upstream: Vec<SocketAddr>,
+    /// Query types forwarded upstream, e.g. `MX,TXT`; others besides A get NODATA
+    #[arg(long, short, value_delimiter = ',')]
+    forward: Vec<RecordType>,
+    /// Domain resolved upstream with its subdomains, may be repeated
+    #[arg(long, short, value_delimiter = ',', value_parser = rules::fqdn)]
+    exclude: Vec<Name>,
+    /// Resolve unmatched names upstream instead of faking them
+    #[arg(long)]
+    real_ip: bool,
+    /// Rule such as `suffix:example.com forward`, may be repeated
+    #[arg(long, short)]
+    rule: Vec<Rule>,
+    /// File with one rule per line, checked before `--rule`
+    #[arg(long)]
+    rule_file: Option<PathBuf>,
+    /// Fixed record such as `nas.lan A 192.168.1.10`, may be repeated
+    #[arg(long)]
+    host: Vec<HostRecord>,
+    /// File in /etc/hosts format answered before any rule, may be repeated
+    #[arg(long)]
+    hosts_file: Vec<PathBuf>,
+    /// How to pick between several matching rules [default: first]
+    #[arg(long, value_enum)]
+    precedence: Option<Precedence>,
+    /// Maximum number of queries answered concurrently [default: 1024]
+    #[arg(long)]
+    concurrency: Option<usize>,
+    /// File the mappings are restored from at startup and saved to
+    #[arg(long)]
+    state: Option<PathBuf>,
+    /// Seconds between two snapshots of the mappings [default: 60]
+    #[arg(long)]
+    snapshot_interval: Option<u64>,
+    /// Number of UDP sockets bound with SO_REUSEPORT [default: 1]
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Append log lines to this file instead of stdout
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Format of log lines [default: text]
+    #[arg(long, value_enum)]
+    log_format: Option<Format>,
+    /// Log every answered query
+    #[arg(long)]
+    log_queries: bool,
+    /// Serve the HTTP admin API and `/metrics` on this address, e.g. `127.0.0.1:8053`
+    #[arg(long)]
+    admin: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default)]
+pub enum MyError {
+    #[default]
+    IpNotEnough,
+    Proto,
+    Ipv4Network,
+    Ipv6Network,
+    EmptyQuery,
+    Listen,
+    Rule(String),
+    Config(String),
+    Mapping(String),
+    /// Opcodes other than QUERY.
+    NotImplemented,
+    /// Classes other than IN and zone transfers.
+    Refused,
+}
+
+impl MyError {
+    /// Response code telling the client why its query failed.
+    fn rcode(&self) -> ResponseCode {
+        match self {
+            MyError::Proto | MyError::EmptyQuery => ResponseCode::FormErr,
+            MyError::NotImplemented => ResponseCode::NotImp,
+            MyError::Refused => ResponseCode::Refused,
+            MyError::IpNotEnough
+            | MyError::Ipv4Network
+            | MyError::Ipv6Network
+            | MyError::Listen
+            | MyError::Rule(_)
+            | MyError::Config(_)
+            | MyError::Mapping(_) => ResponseCode::ServFail,
+        }
+    }
+}
+
+impl Display for MyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MyError::IpNotEnough => write!(f, "no free address left in the pool"),
+            MyError::Proto => write!(f, "malformed message"),
+            MyError::Ipv4Network => write!(f, "not an IPv4 CIDR"),
+            MyError::Ipv6Network => write!(f, "not an IPv6 CIDR"),
+            MyError::EmptyQuery => write!(f, "no question in the query"),
+            MyError::Listen => write!(f, "the listen address resolves to nothing"),
+            MyError::Rule(msg) | MyError::Config(msg) | MyError::Mapping(msg) => {
+                write!(f, "{}", msg)
+            }
+            MyError::NotImplemented => write!(f, "opcode not implemented"),
+            MyError::Refused => write!(f, "query refused"),
+        }
+    }
+}
+
+impl Error for MyError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+        sync::{Arc, Mutex, RwLock},
+    };
+
+    use hickory_resolver::proto::{
+        op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+        rr::{DNSClass, Name, RData, RecordType},
+        serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use tokio::sync::Semaphore;
+
+    use crate::{
+        MyError, Server, Settings, Transport,
+        allocator::{Allocator, Allocators, Context},
+        config::{Config, TtlConfig},
+        hosts::Hosts,
+        mapping::Mapping,
+        metrics::Metrics,
+        pool::{Ipv4, Ipv6, Pool},
+        query, respond,
+        rules::{Action, Precedence, Rules},
+    };
+
+    pub const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 53000));
+
+    pub fn server(cidr: &str) -> Server {
+        Server {
+            mapping: Mutex::new(Mapping::new(Ipv4::from_cidr(cidr).unwrap())),
+            mapping6: None,
+            pools: BTreeMap::new(),
+            settings: RwLock::new(Arc::new(Settings {
+                upstream: None,
+                forward: vec![],
+                rules: Rules::new(vec![], Precedence::First, Action::Fake),
+                hosts: Hosts::default(),
+                ttl: TtlConfig::default(),
+            })),
+            concurrency: Arc::new(Semaphore::new(16)),
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn set_rules(server: &Server, list: &str) {
+        let rules = Rules::new(
+            Rules::parse_list(list).unwrap(),
+            Precedence::First,
+            Action::Fake,
+        );
+        let mut settings = server.settings.write().unwrap();
+        *settings = Arc::new(Settings {
+            upstream: None,
+            forward: vec![],
+            rules,
+            hosts: Hosts::default(),
+            ttl: settings.ttl,
+        });
+    }
+
+    pub fn request(name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message.set_id(7);
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        message
+    }
+
+    #[tokio::test]
+    async fn ptr_in_pool() {
+        let server = server("10.0.0.0/24");
+        let (response, _) = query(&request("example.com.", RecordType::A), &server, CLIENT)
+            .await
+            .unwrap();
+        let RData::A(ip) = response.answers()[0].data() else {
+            panic!("expected an A record");
+        };
+        let octets = ip.0.octets();
+        let arpa = format!(
+            "{}.{}.{}.{}.in-addr.arpa.",
+            octets[3], octets[2], octets[1], octets[0]
+        );
+
+        let (response, _) = query(&request(&arpa, RecordType::PTR), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(response.id(), 7);
+        let RData::PTR(ptr) = response.answers()[0].data() else {
+            panic!("expected a PTR record");
+        };
+        assert_eq!(ptr.0, Name::from_ascii("example.com.").unwrap());
+
+        let arpa = request("0.0.0.10.in-addr.arpa.", RecordType::PTR);
+        let (response, _) = query(&arpa, &server, CLIENT).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn nodata_for_other_types() {
+        let server = server("10.0.0.0/24");
+        for query_type in [RecordType::AAAA, RecordType::MX, RecordType::TXT] {
+            let (response, _) = query(&request("example.com.", query_type), &server, CLIENT)
+                .await
+                .unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(response.answers().is_empty());
+            assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+        }
+    }
+
+    #[tokio::test]
+    async fn aaaa_from_ipv6_pool() {
+        let mut server = server("10.0.0.0/24");
+        server.mapping6 = Some(Mutex::new(Mapping::new(
+            Ipv6::from_cidr("fd00::/64").unwrap(),
+        )));
+        let (response, _) = query(&request("example.com.", RecordType::AAAA), &server, CLIENT)
+            .await
+            .unwrap();
+        let RData::AAAA(ip) = response.answers()[0].data() else {
+            panic!("expected an AAAA record");
+        };
+        let (again, _) = query(&request("example.com.", RecordType::AAAA), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(again.answers()[0].data(), &RData::AAAA(*ip));
+
+        let arpa = request(&Name::from(ip.0).to_string(), RecordType::PTR);
+        let (response, _) = query(&arpa, &server, CLIENT).await.unwrap();
+        let RData::PTR(ptr) = response.answers()[0].data() else {
+            panic!("expected a PTR record");
+        };
+        assert_eq!(ptr.0, Name::from_ascii("example.com.").unwrap());
+    }
+
+    #[tokio::test]
+    async fn block_and_static_rules() {
+        let server = server("10.0.0.0/24");
+        let list = "exact:ads.example.com block\nexact:nas.lan static:192.168.1.10";
+        set_rules(&server, list);
+
+        let (response, action) =
+            query(&request("ads.example.com.", RecordType::A), &server, CLIENT)
+                .await
+                .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(action, "block");
+
+        let (response, _) = query(&request("nas.lan.", RecordType::A), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A("192.168.1.10".parse().unwrap())
+        );
+
+        let (response, _) = query(&request("nas.lan.", RecordType::AAAA), &server, CLIENT)
+            .await
+            .unwrap();
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+    }
+
+    #[tokio::test]
+    async fn rules_pick_the_pool() {
+        let mut server = server("10.0.0.0/24");
+        server.mapping6 = Some(Mutex::new(Mapping::new(
+            Ipv6::from_cidr("fd00::/64").unwrap(),
+        )));
+        let tenant = Ipv4::from_cidr("100.64.0.0/24").unwrap();
+        server
+            .pools
+            .insert("tenant-a".to_string(), Mutex::new(Mapping::new(tenant)));
+        set_rules(&server, "suffix:corp.lan fake:tenant-a");
+
+        let a = |name: &str| {
+            let server = &server;
+            let request = request(name, RecordType::A);
+            async move {
+                let (response, action) = query(&request, server, CLIENT).await.unwrap();
+                assert_eq!(action, "fake");
+                response.answers()[0].data().as_a().unwrap().0
+            }
+        };
+        let ip = a("git.corp.lan.").await;
+        assert_eq!(ip.octets()[..3], [100, 64, 0]);
+        assert_eq!(a("example.com.").await.octets()[..3], [10, 0, 0]);
+
+        let [w, x, y, z] = ip.octets();
+        let arpa = request(&format!("{z}.{y}.{x}.{w}.in-addr.arpa."), RecordType::PTR);
+        let (response, _) = query(&arpa, &server, CLIENT).await.unwrap();
+        assert_eq!(
+            response.answers()[0].data().as_ptr().unwrap().0,
+            Name::from_ascii("git.corp.lan.").unwrap()
+        );
+
+        let (response, _) = query(&request("git.corp.lan.", RecordType::AAAA), &server, CLIENT)
+            .await
+            .unwrap();
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pools_leave_out_excluded_addresses() {
+        let config = Config::parse(
+            "[pool]\ncidr = \"10.0.0.0/29\"\nexclude = [\"10.0.0.4/30\"]\n\n\
+             [pools.a]\ncidr = \"10.0.0.4/30\"\nexclude = [\"10.0.0.5\"]\n",
+        )
+        .unwrap();
+        let server = Server::new(&config, &config).await.unwrap();
+        let mut ips = (0..8)
+            .flat_map(|i| {
+                let name = Name::from_ascii(format!("{i}.example.com.")).unwrap();
+                [None, Some("a")].map(|pool| {
+                    let mut mapping = server.pool(pool).unwrap().lock().unwrap();
+                    mapping.get_or_insert(&name).unwrap().octets()[3]
+                })
+            })
+            .collect::<Vec<_>>();
+        ips.sort();
+        ips.dedup();
+        assert_eq!(ips, [1, 2, 3, 6]);
+    }
+
+    #[tokio::test]
+    async fn named_pools_hash_too() {
+        let config = Config::parse(
+            "[pool]\ncidr = \"10.0.0.0/16\"\nallocation = \"hash\"\n\
+             hash_key = \"000102030405060708090a0b0c0d0e0f\"\n\n\
+             [pools.a]\ncidr = \"10.1.0.0/16\"\n",
+        )
+        .unwrap();
+        let (one, two) = (
+            Server::new(&config, &config).await.unwrap(),
+            Server::new(&config, &config).await.unwrap(),
+        );
+        for i in 0..8 {
+            let name = Name::from_ascii(format!("{i}.example.com.")).unwrap();
+            let ip = |server: &Server| {
+                let mut mapping = server.pool(Some("a")).unwrap().lock().unwrap();
+                mapping.get_or_insert(&name).unwrap()
+            };
+            assert_eq!(ip(&one), ip(&two), "{name}");
+        }
+    }
+
+    /// Hands out the highest free address of every pool.
+    struct Top;
+
+    impl<P: Pool> Allocator<P> for Top {
+        fn allocate(
+            &mut self,
+            pool: &P,
+            used: &dyn Fn(&P::Addr) -> bool,
+            _context: &Context,
+        ) -> Result<P::Addr, MyError> {
+            (0..pool.capacity())
+                .rev()
+                .map(|n| pool.nth(n))
+                .find(|ip| !used(ip))
+                .ok_or(MyError::IpNotEnough)
+        }
+    }
+
+    impl Allocators for Top {
+        fn ipv4(&self, _pool: &str) -> Box<dyn Allocator<Ipv4>> {
+            Box::new(Top)
+        }
+
+        fn ipv6(&self) -> Box<dyn Allocator<Ipv6>> {
+            Box::new(Top)
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_allocators() {
+        let config = Config::parse(
+            "[pool]\ncidr = \"10.0.0.0/29\"\ncidr6 = \"fd00::/126\"\n\n\
+             [pools.a]\ncidr = \"10.0.1.0/30\"\n",
+        )
+        .unwrap();
+        let server = Server::new(&config, &Top).await.unwrap();
+        let name = Name::from_ascii("example.com.").unwrap();
+        let ip = |pool| {
+            server
+                .pool(pool)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .get_or_insert(&name)
+        };
+        assert_eq!(ip(None).unwrap(), "10.0.0.6".parse::<Ipv4Addr>().unwrap());
+        assert_eq!(
+            ip(Some("a")).unwrap(),
+            "10.0.1.2".parse::<Ipv4Addr>().unwrap()
+        );
+        let mapping6 = server.mapping6.as_ref().unwrap();
+        assert_eq!(
+            mapping6.lock().unwrap().get_or_insert(&name).unwrap(),
+            "fd00::3".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn hosts_before_rules() {
+        let server = server("10.0.0.0/24");
+        set_rules(&server, "suffix:lan block");
+        let mut hosts = Hosts::default();
+        hosts
+            .insert("nas.lan A 192.168.1.10".parse().unwrap())
+            .unwrap();
+        let settings = server.settings();
+        *server.settings.write().unwrap() = Arc::new(Settings {
+            upstream: None,
+            forward: vec![],
+            rules: Rules::new(
+                Rules::parse_list("suffix:lan block").unwrap(),
+                Precedence::First,
+                Action::Fake,
+            ),
+            hosts,
+            ttl: settings.ttl,
+        });
+
+        let (response, action) = query(&request("nas.lan.", RecordType::A), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(action, "hosts");
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A("192.168.1.10".parse().unwrap())
+        );
+
+        let (response, _) = query(&request("nas.lan.", RecordType::MX), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+
+        let (response, _) = query(&request("tv.lan.", RecordType::A), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn global_negative_and_rule_ttls() {
+        let server = server("10.0.0.0/24");
+        let list = "exact:nas.lan static:192.168.1.10 ttl=60\nexact:ads.example.com block ttl=5";
+        *server.settings.write().unwrap() = Arc::new(Settings {
+            upstream: None,
+            forward: vec![],
+            rules: Rules::new(
+                Rules::parse_list(list).unwrap(),
+                Precedence::First,
+                Action::Fake,
+            ),
+            hosts: Hosts::default(),
+            ttl: TtlConfig {
+                answer: 300,
+                fake: Some(1),
+                negative: 30,
+            },
+        });
+
+        let ttl = |name: String, query_type| {
+            let server = &server;
+            async move {
+                let (response, _) = query(&request(&name, query_type), server, CLIENT)
+                    .await
+                    .unwrap();
+                match response.answers().first() {
+                    Some(answer) => answer.ttl(),
+                    None => {
+                        let soa = &response.name_servers()[0];
+                        assert_eq!(soa.data().as_soa().unwrap().minimum(), soa.ttl());
+                        soa.ttl()
+                    }
+                }
+            }
+        };
+
+        // fake answers expire quickly, the mapping stays
+        assert_eq!(ttl("example.com.".into(), RecordType::A).await, 1);
+        assert_eq!(ttl("example.com.".into(), RecordType::MX).await, 30);
+        assert_eq!(ttl("nas.lan.".into(), RecordType::A).await, 60);
+        assert_eq!(ttl("nas.lan.".into(), RecordType::AAAA).await, 30);
+        assert_eq!(ttl("ads.example.com.".into(), RecordType::A).await, 5);
+
+        let name = Name::from_ascii("example.com.").unwrap();
+        let ip = server.mapping.lock().unwrap().get(&name).unwrap().ip;
+        let [a, b, c, d] = ip.octets();
+        let arpa = format!("{d}.{c}.{b}.{a}.in-addr.arpa.");
+        assert_eq!(ttl(arpa, RecordType::PTR).await, 300);
+    }
+
+    #[tokio::test]
+    async fn edns_payload_and_truncation() {
+        let server = server("10.0.0.0/24");
+        let ips = |n| (1..=n).map(|i| format!("192.0.2.{i}")).collect::<Vec<_>>();
+        let list = format!(
+            "exact:big.lan static:{}\nexact:huge.lan static:{}",
+            ips(60).join(","),
+            ips(100).join(",")
+        );
+        set_rules(&server, &list);
+
+        let mut plain = request("big.lan.", RecordType::A);
+        let bytes = respond(&plain.to_bytes().unwrap(), &server, CLIENT, Transport::Udp)
+            .await
+            .unwrap();
+        let response = Message::from_bytes(&bytes).unwrap();
+        assert!(bytes.len() <= 512);
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+
+        let bytes = respond(&plain.to_bytes().unwrap(), &server, CLIENT, Transport::Tcp)
+            .await
+            .unwrap();
+        assert_eq!(Message::from_bytes(&bytes).unwrap().answers().len(), 60);
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(4096);
+        plain.set_edns(edns.clone());
+        let bytes = respond(&plain.to_bytes().unwrap(), &server, CLIENT, Transport::Udp)
+            .await
+            .unwrap();
+        let response = Message::from_bytes(&bytes).unwrap();
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 60);
+        assert!(response.extensions().is_some());
+
+        // a larger advertised payload is still capped at our own
+        let mut huge = request("huge.lan.", RecordType::A);
+        huge.set_edns(edns);
+        let bytes = respond(&huge.to_bytes().unwrap(), &server, CLIENT, Transport::Udp)
+            .await
+            .unwrap();
+        assert!(bytes.len() <= super::UDP_PAYLOAD as usize);
+        assert!(Message::from_bytes(&bytes).unwrap().truncated());
+    }
+
+    #[tokio::test]
+    async fn edns_bad_version() {
+        let server = server("10.0.0.0/24");
+        let mut message = request("example.com.", RecordType::A);
+        let mut edns = Edns::new();
+        edns.set_version(1);
+        message.set_edns(edns);
+
+        let bytes = respond(
+            &message.to_bytes().unwrap(),
+            &server,
+            CLIENT,
+            Transport::Udp,
+        )
+        .await
+        .unwrap();
+        let response = Message::from_bytes(&bytes).unwrap();
+        // BADVERS shares its code with BADSIG, which is what the decoder picks
+        assert_eq!(
+            u16::from(response.response_code()),
+            u16::from(ResponseCode::BADVERS)
+        );
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_flags_and_question_case() {
+        let server = server("10.0.0.0/24");
+        let mut message = request("WwW.ExAmPlE.cOm.", RecordType::A);
+        message.set_recursion_desired(true);
+        let bytes = message.to_bytes().unwrap();
+
+        let answer = respond(&bytes, &server, CLIENT, Transport::Udp)
+            .await
+            .unwrap();
+        let response = Message::from_bytes(&answer).unwrap();
+        assert!(response.recursion_desired());
+        assert!(response.authoritative());
+        // no upstream, no recursion
+        assert!(!response.recursion_available());
+        // the question goes back byte for byte, right after the header
+        let question = &bytes[12..];
+        assert_eq!(&answer[12..12 + question.len()], question);
+        assert_eq!(response.answers()[0].name().to_string(), "WwW.ExAmPlE.cOm.");
+
+        message.set_recursion_desired(false);
+        let (response, _) = query(&message, &server, CLIENT).await.unwrap();
+        assert!(!response.recursion_desired());
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        let server = server("10.0.0.0/24");
+        let answer = |bytes: Vec<u8>| {
+            let server = &server;
+            async move {
+                let bytes = respond(&bytes, server, CLIENT, Transport::Udp).await?;
+                Some(Message::from_bytes(&bytes).unwrap())
+            }
+        };
+
+        let mut notify = request("example.com.", RecordType::SOA);
+        notify.set_op_code(OpCode::Notify);
+        let response = answer(notify.to_bytes().unwrap()).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NotImp);
+        assert_eq!(response.op_code(), OpCode::Notify);
+
+        let mut chaos = request("version.bind.", RecordType::TXT);
+        chaos.queries_mut()[0].set_query_class(DNSClass::CH);
+        let response = answer(chaos.to_bytes().unwrap()).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert_eq!(response.queries().len(), 1);
+
+        let mut empty = request("example.com.", RecordType::A);
+        empty.take_queries();
+        let response = answer(empty.to_bytes().unwrap()).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+
+        // header intact, question cut short
+        let bytes = request("example.com.", RecordType::A).to_bytes().unwrap();
+        let response = answer(bytes[..16].to_vec()).await.unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(response.id(), 7);
+
+        assert!(answer(bytes[..8].to_vec()).await.is_none());
+        let mut reply = request("example.com.", RecordType::A);
+        reply.set_message_type(MessageType::Response);
+        assert!(answer(reply.to_bytes().unwrap()).await.is_none());
+    }
+}
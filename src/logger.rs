@@ -0,0 +1,396 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use clap::ValueEnum;
+use hickory_resolver::proto::{
+    op::ResponseCode,
+    rr::{Name, Record, RecordType},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::LogConfig;
+
+/// Socket of the local syslog daemon.
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility `daemon` of RFC 3164.
+const SYSLOG_DAEMON: u8 = 3;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `key=value` pairs after a timestamp and level.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// When a log file is started afresh, besides `log.max_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotate {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Info,
+    Warn,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+        }
+    }
+
+    /// Syslog severity.
+    fn severity(self) -> u8 {
+        match self {
+            Level::Info => 6,
+            Level::Warn => 4,
+        }
+    }
+}
+
+/// Everything logged about one answered query.
+pub struct QueryLog<'a> {
+    pub client: SocketAddr,
+    pub qname: &'a Name,
+    pub qtype: RecordType,
+    /// Rule action that produced the answer, see [`crate::rules::Action::name`].
+    pub action: &'static str,
+    pub answers: &'a [Record],
+    pub rcode: ResponseCode,
+    pub latency: Duration,
+}
+
+struct Logger {
+    format: Format,
+    queries: bool,
+    sink: Mutex<Sink>,
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+/// Sets up logging as configured by `[log]`. Until this is called, and in
+/// tests, text lines go to stdout.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let sink = if config.syslog {
+        syslog()?
+    } else if let Some(path) = &config.file {
+        Sink::File(RotatingFile::open(
+            path.clone(),
+            config.max_size,
+            config.rotate,
+            config.keep,
+        )?)
+    } else {
+        Sink::Stdout
+    };
+
+    let _ = LOGGER.set(Logger {
+        format: config.format,
+        queries: config.queries,
+        sink: Mutex::new(sink),
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn syslog() -> io::Result<Sink> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect(SYSLOG_SOCKET)?;
+    Ok(Sink::Syslog(socket))
+}
+
+#[cfg(not(unix))]
+fn syslog() -> io::Result<Sink> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "syslog is only supported on unix",
+    ))
+}
+
+/// Logs a free-form message, use the `info!` and `warn!` macros.
+pub fn message(level: Level, args: fmt::Arguments) {
+    let now = Local::now();
+    let format = LOGGER.get().map_or(Format::Text, |logger| logger.format);
+    let line = match format {
+        Format::Text => format!(
+            "{} {} {}",
+            timestamp(&now),
+            level.name().to_uppercase(),
+            args
+        ),
+        Format::Json => json!({
+            "ts": timestamp(&now),
+            "level": level.name(),
+            "msg": args.to_string(),
+        })
+        .to_string(),
+    };
+    write(level, &line, &now);
+}
+
+/// Logs an answered query if `log.queries` is enabled.
+pub fn query(entry: &QueryLog) {
+    let Some(logger) = LOGGER.get().filter(|logger| logger.queries) else {
+        return;
+    };
+    let now = Local::now();
+    write(Level::Info, &format_query(logger.format, entry, &now), &now);
+}
+
+fn format_query(format: Format, entry: &QueryLog, now: &DateTime<Local>) -> String {
+    let answers = entry
+        .answers
+        .iter()
+        .map(|record| record.data().to_string())
+        .collect::<Vec<_>>();
+    let latency_ms = entry.latency.as_secs_f64() * 1000.0;
+    let rcode = format!("{:?}", entry.rcode);
+
+    match format {
+        Format::Text => format!(
+            "{} INFO query client={} qname={} qtype={} action={} rcode={} answer={} latency_ms={:.3}",
+            timestamp(now),
+            entry.client,
+            entry.qname,
+            entry.qtype,
+            entry.action,
+            rcode,
+            if answers.is_empty() {
+                "-".to_string()
+            } else {
+                answers.join(",")
+            },
+            latency_ms,
+        ),
+        Format::Json => json!({
+            "ts": timestamp(now),
+            "level": "info",
+            "msg": "query",
+            "client": entry.client.to_string(),
+            "qname": entry.qname.to_string(),
+            "qtype": entry.qtype.to_string(),
+            "action": entry.action,
+            "rcode": rcode,
+            "answer": answers,
+            "latency_ms": (latency_ms * 1000.0).round() / 1000.0,
+        })
+        .to_string(),
+    }
+}
+
+fn timestamp(now: &DateTime<Local>) -> String {
+    now.to_rfc3339_opts(SecondsFormat::Millis, false)
+}
+
+fn write(level: Level, line: &str, now: &DateTime<Local>) {
+    let Some(logger) = LOGGER.get() else {
+        println!("{line}");
+        return;
+    };
+    let result = match &mut *logger.sink.lock().unwrap() {
+        Sink::Stdout => writeln!(io::stdout().lock(), "{line}"),
+        Sink::File(file) => file.write_line(line, now),
+        #[cfg(unix)]
+        Sink::Syslog(socket) => {
+            let priority = SYSLOG_DAEMON * 8 + level.severity();
+            let pid = std::process::id();
+            socket
+                .send(format!("<{priority}>fake-dns[{pid}]: {line}").as_bytes())
+                .map(|_| ())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("failed to write log line: {e}");
+    }
+}
+
+/// Log file that is renamed to `<file>.1`, `<file>.2`, ... once it grows
+/// past `max_size` bytes or the `rotate` period ends.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 0 disables rotation by size.
+    max_size: u64,
+    rotate: Rotate,
+    /// Period the current file was started in.
+    period: String,
+    /// Number of rotated files kept.
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, rotate: Rotate, keep: usize) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            rotate,
+            period: period(rotate, &Local::now()),
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str, now: &DateTime<Local>) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        let period = period(self.rotate, now);
+        if (self.max_size > 0 && self.size > 0 && self.size + len > self.max_size)
+            || period != self.period
+        {
+            self.rotate()?;
+            self.period = period;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                rename_if_exists(&rotated(n), &rotated(n + 1))?;
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = File::options().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn period(rotate: Rotate, now: &DateTime<Local>) -> String {
+    match rotate {
+        Rotate::Never => String::new(),
+        Rotate::Hourly => now.format("%Y%m%d%H").to_string(),
+        Rotate::Daily => now.format("%Y%m%d").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone};
+    use hickory_resolver::proto::{
+        op::ResponseCode,
+        rr::{Name, RData, Record, RecordType},
+    };
+
+    use super::{Format, QueryLog, Rotate, RotatingFile, format_query};
+
+    #[test]
+    fn query_lines() {
+        let name = Name::from_ascii("a.example.com.").unwrap();
+        let answers = [Record::from_rdata(
+            name.clone(),
+            600,
+            RData::A("10.0.0.1".parse().unwrap()),
+        )];
+        let entry = QueryLog {
+            client: "127.0.0.1:53000".parse().unwrap(),
+            qname: &name,
+            qtype: RecordType::A,
+            action: "fake",
+            answers: &answers,
+            rcode: ResponseCode::NoError,
+            latency: Duration::from_micros(1500),
+        };
+        let now = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+
+        let text = format_query(Format::Text, &entry, &now);
+        assert!(text.ends_with(
+            "INFO query client=127.0.0.1:53000 qname=a.example.com. qtype=A \
+             action=fake rcode=NoError answer=10.0.0.1 latency_ms=1.500"
+        ));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format_query(Format::Json, &entry, &now)).unwrap();
+        assert_eq!(json["client"], "127.0.0.1:53000");
+        assert_eq!(json["qname"], "a.example.com.");
+        assert_eq!(json["qtype"], "A");
+        assert_eq!(json["action"], "fake");
+        assert_eq!(json["rcode"], "NoError");
+        assert_eq!(json["answer"][0], "10.0.0.1");
+        assert_eq!(json["latency_ms"], 1.5);
+        assert!(
+            json["ts"]
+                .as_str()
+                .unwrap()
+                .starts_with("2024-05-01T12:00:00.000")
+        );
+    }
+
+    #[test]
+    fn rotate_by_size_and_period() {
+        let dir = std::env::temp_dir().join(format!("fake-dns-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fake-dns.log");
+        let rotated = |n: usize| dir.join(format!("fake-dns.log.{n}"));
+
+        let mut file = RotatingFile::open(path.clone(), 20, Rotate::Daily, 2).unwrap();
+        let day = Local::now();
+        for line in ["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line, &day).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(std::fs::read_to_string(rotated(1)).unwrap(), "third line\n");
+        assert_eq!(
+            std::fs::read_to_string(rotated(2)).unwrap(),
+            "second line\n"
+        );
+        assert!(!rotated(3).exists());
+
+        file.write_line("next day", &(day + chrono::Duration::days(1)))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "next day\n");
+        assert_eq!(
+            std::fs::read_to_string(rotated(1)).unwrap(),
+            "fourth line\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
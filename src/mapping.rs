@@ -0,0 +1,325 @@
+use std::collections::{BTreeMap, HashMap};
+
+use hickory_resolver::proto::rr::Name;
+
+use crate::{
+    MyError,
+    allocator::{Allocator, Context},
+    pool::Pool,
+};
+
+/// Bidirectional domain <-> fake ip table.
+///
+/// Every name gets exactly one address out of the pool, repeated queries
+/// return the same address and no two names share one. New names get their
+/// address from the [`Allocator`]. Once the pool is exhausted the least
+/// recently queried name is evicted to make room. Pinned names are never
+/// evicted.
+pub struct Mapping<P: Pool> {
+    pool: P,
+    allocator: Box<dyn Allocator<P>>,
+    by_name: HashMap<Name, Entry<P::Addr>>,
+    by_ip: HashMap<P::Addr, Name>,
+    /// Last use tick -> name, oldest first.
+    lru: BTreeMap<u64, Name>,
+    tick: u64,
+    evictions: u64,
+}
+
+pub struct Entry<A> {
+    pub ip: A,
+    pub pinned: bool,
+    last_used: u64,
+}
+
+impl<P: Pool> Mapping<P> {
+    pub fn new(pool: P) -> Self {
+        Self::with_allocator(pool, Box::new(crate::allocator::Random))
+    }
+
+    pub fn with_allocator(pool: P, allocator: Box<dyn Allocator<P>>) -> Self {
+        Self {
+            pool,
+            allocator,
+            by_name: HashMap::new(),
+            by_ip: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Returns the address mapped to `name`, allocating a fresh one on first use.
+    pub fn get_or_insert(&mut self, name: &Name) -> Result<P::Addr, MyError> {
+        self.get_or_allocate(&Context { name, client: None })
+    }
+
+    /// Like [`get_or_insert`](Self::get_or_insert), handing `context` to the
+    /// allocator when the name is new.
+    pub fn get_or_allocate(&mut self, context: &Context) -> Result<P::Addr, MyError> {
+        self.tick += 1;
+
+        if let Some(entry) = self.by_name.get_mut(context.name) {
+            if let Some(name) = self.lru.remove(&entry.last_used) {
+                self.lru.insert(self.tick, name);
+            }
+            entry.last_used = self.tick;
+            return Ok(entry.ip);
+        }
+
+        if self.by_ip.len() as u128 >= self.pool.capacity() {
+            self.evict()?;
+        }
+        let by_ip = &self.by_ip;
+        let ip = self
+            .allocator
+            .allocate(&self.pool, &|ip| by_ip.contains_key(ip), context)?;
+        if !self.pool.allocatable(ip) || self.by_ip.contains_key(&ip) {
+            return Err(MyError::Mapping(format!(
+                "allocator handed out {ip}, taken or outside the pool"
+            )));
+        }
+
+        self.insert(context.name.to_lowercase(), ip, false);
+        Ok(ip)
+    }
+
+    /// Re-adds a mapping from a snapshot as the most recently used one.
+    ///
+    /// Returns false, leaving the table untouched, when `ip` cannot be handed
+    /// out by the current pool or either side is already mapped.
+    pub fn restore(&mut self, name: &Name, ip: P::Addr, pinned: bool) -> bool {
+        if !self.pool.allocatable(ip)
+            || self.by_ip.contains_key(&ip)
+            || self.by_name.contains_key(name)
+        {
+            return false;
+        }
+        self.tick += 1;
+        self.insert(name.to_lowercase(), ip, pinned);
+        true
+    }
+
+    /// Maps `name` to `ip`, or to the address it would get from
+    /// [`get_or_insert`](Self::get_or_insert) when `ip` is `None`, and
+    /// exempts the mapping from eviction. A name holding `ip` before loses it.
+    pub fn pin(&mut self, name: &Name, ip: Option<P::Addr>) -> Result<P::Addr, MyError> {
+        let ip = match ip {
+            None => self.get_or_insert(name)?,
+            Some(ip) if self.get(name).is_some_and(|entry| entry.ip == ip) => ip,
+            Some(ip) => {
+                if !self.pool.allocatable(ip) {
+                    return Err(MyError::Mapping(format!("{ip} is outside the pool")));
+                }
+                if let Some(other) = self.by_ip.get(&ip).cloned() {
+                    self.remove(&other);
+                }
+                self.remove(name);
+                self.tick += 1;
+                self.insert(name.to_lowercase(), ip, true);
+                ip
+            }
+        };
+        if let Some(entry) = self.by_name.get_mut(name) {
+            entry.pinned = true;
+        }
+        Ok(ip)
+    }
+
+    /// Drops the mapping of `name`, returning its address.
+    pub fn remove(&mut self, name: &Name) -> Option<P::Addr> {
+        let entry = self.by_name.remove(name)?;
+        self.pool.set_used(entry.ip, false);
+        self.allocator.release(entry.ip);
+        self.by_ip.remove(&entry.ip);
+        self.lru.remove(&entry.last_used);
+        Some(entry.ip)
+    }
+
+    /// Drops every mapping, pinned ones only if `pinned` is set. Returns the
+    /// number of mappings dropped.
+    pub fn flush(&mut self, pinned: bool) -> usize {
+        let names = self
+            .by_name
+            .iter()
+            .filter(|(_, entry)| pinned || !entry.pinned)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in &names {
+            self.remove(name);
+        }
+        names.len()
+    }
+
+    /// The mapping of `name`, without counting as a use.
+    pub fn get(&self, name: &Name) -> Option<&Entry<P::Addr>> {
+        self.by_name.get(name)
+    }
+
+    /// All mappings, least recently used first.
+    pub fn entries(&self) -> impl Iterator<Item = (&Name, &Entry<P::Addr>)> {
+        self.lru.values().map(|name| (name, &self.by_name[name]))
+    }
+
+    fn insert(&mut self, name: Name, ip: P::Addr, pinned: bool) {
+        self.pool.set_used(ip, true);
+        self.by_ip.insert(ip, name.clone());
+        self.lru.insert(self.tick, name.clone());
+        self.by_name.insert(
+            name,
+            Entry {
+                ip,
+                pinned,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    pub fn domain(&self, ip: P::Addr) -> Option<&Name> {
+        self.by_ip.get(&ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Number of addresses the pool can hand out.
+    pub fn capacity(&self) -> u128 {
+        self.pool.capacity()
+    }
+
+    /// Mappings dropped so far because the pool was exhausted.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Whether `ip` belongs to the pool this table allocates from.
+    pub fn contains(&self, ip: P::Addr) -> bool {
+        self.pool.contains(ip)
+    }
+
+    /// Drops the least recently used mapping that is not pinned, freeing its
+    /// address.
+    fn evict(&mut self) -> Result<(), MyError> {
+        let name = self
+            .lru
+            .values()
+            .find(|name| !self.by_name[*name].pinned)
+            .cloned()
+            .ok_or(MyError::IpNotEnough)?;
+        let ip = self.remove(&name).ok_or(MyError::IpNotEnough)?;
+        self.evictions += 1;
+        warn!(
+            "pool exhausted, evicted {} from {} ({} evictions)",
+            name, ip, self.evictions
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::rr::Name;
+
+    use super::Mapping;
+    use crate::pool::{Ipv4, Ipv6};
+
+    #[test]
+    fn stable_and_unique() {
+        let mut mapping = Mapping::new(Ipv4::from_cidr("10.0.0.0/24").unwrap());
+        let a = Name::from_ascii("a.example.com.").unwrap();
+        let b = Name::from_ascii("B.Example.com.").unwrap();
+
+        let ip_a = mapping.get_or_insert(&a).unwrap();
+        let ip_b = mapping.get_or_insert(&b).unwrap();
+        assert_ne!(ip_a, ip_b);
+        assert_eq!(mapping.get_or_insert(&a).unwrap(), ip_a);
+        assert_eq!(
+            mapping
+                .get_or_insert(&Name::from_ascii("b.example.com.").unwrap())
+                .unwrap(),
+            ip_b
+        );
+        assert_eq!(mapping.domain(ip_a), Some(&a));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut mapping = Mapping::new(Ipv4::from_cidr("10.0.0.0/30").unwrap());
+        let a = Name::from_ascii("a.").unwrap();
+        let b = Name::from_ascii("b.").unwrap();
+        let c = Name::from_ascii("c.").unwrap();
+
+        let ip_a = mapping.get_or_insert(&a).unwrap();
+        let ip_b = mapping.get_or_insert(&b).unwrap();
+        mapping.get_or_insert(&a).unwrap();
+
+        assert_eq!(mapping.get_or_insert(&c).unwrap(), ip_b);
+        assert_eq!(mapping.evictions, 1);
+        assert_eq!(mapping.domain(ip_b), Some(&c));
+        assert_eq!(mapping.domain(ip_a), Some(&a));
+    }
+
+    #[test]
+    fn pinned_survive_eviction_and_flush() {
+        let mut mapping = Mapping::new(Ipv4::from_cidr("10.0.0.0/30").unwrap());
+        let a = Name::from_ascii("a.").unwrap();
+        let b = Name::from_ascii("b.").unwrap();
+        let c = Name::from_ascii("c.").unwrap();
+        let ip: std::net::Ipv4Addr = "10.0.0.2".parse().unwrap();
+
+        assert!(mapping.pin(&a, Some("10.0.0.3".parse().unwrap())).is_err());
+        assert_eq!(mapping.pin(&a, Some(ip)).unwrap(), ip);
+        let ip_b = mapping.get_or_insert(&b).unwrap();
+        mapping.get_or_insert(&b).unwrap();
+
+        // a is the least recently used but pinned, b goes instead
+        assert_eq!(mapping.get_or_insert(&c).unwrap(), ip_b);
+        assert!(mapping.get(&b).is_none());
+        assert_eq!(mapping.get(&a).unwrap().ip, ip);
+
+        assert_eq!(mapping.flush(false), 1);
+        assert_eq!(mapping.domain(ip), Some(&a));
+        assert_eq!(mapping.remove(&a), Some(ip));
+        assert_eq!(mapping.entries().count(), 0);
+    }
+
+    #[test]
+    fn ipv6_stable_and_unique() {
+        let mut mapping = Mapping::new(Ipv6::from_cidr("fd00::/64").unwrap());
+        let a = Name::from_ascii("a.example.com.").unwrap();
+        let b = Name::from_ascii("b.example.com.").unwrap();
+
+        let ip_a = mapping.get_or_insert(&a).unwrap();
+        assert_ne!(mapping.get_or_insert(&b).unwrap(), ip_a);
+        assert_eq!(mapping.get_or_insert(&a).unwrap(), ip_a);
+        assert_eq!(mapping.domain(ip_a), Some(&a));
+    }
+
+    #[test]
+    fn restore_validates_against_pool() {
+        let mut mapping = Mapping::new(Ipv4::from_cidr("10.0.0.0/24").unwrap());
+        let a = Name::from_ascii("a.").unwrap();
+        let b = Name::from_ascii("b.").unwrap();
+
+        assert!(!mapping.restore(&a, "10.0.1.1".parse().unwrap(), false));
+        assert!(!mapping.restore(&a, "10.0.0.255".parse().unwrap(), false));
+        assert!(mapping.restore(&a, "10.0.0.1".parse().unwrap(), false));
+        assert!(!mapping.restore(&b, "10.0.0.1".parse().unwrap(), false));
+        assert!(mapping.restore(&b, "10.0.0.2".parse().unwrap(), false));
+
+        assert_eq!(
+            mapping.get_or_insert(&a).unwrap(),
+            "10.0.0.1".parse::<std::net::Ipv4Addr>().unwrap()
+        );
+        let entries = mapping
+            .entries()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![b, a]);
+    }
+}
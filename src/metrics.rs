@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{DEFAULT_POOL, Server, mapping::Mapping, pool::Pool};
+
+/// Upper bounds of the latency histogram buckets in seconds.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Counters exported on `/metrics` in the Prometheus text format. Pool
+/// gauges are read from the mappings when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    pub queries_by_type: Family,
+    pub queries_by_rcode: Family,
+    pub queries_by_action: Family,
+    pub parse_failures: Family,
+    pub send_failures: Family,
+    pub latency: Histogram,
+}
+
+/// Counter with a single label.
+#[derive(Default)]
+pub struct Family(Mutex<BTreeMap<String, u64>>);
+
+impl Family {
+    pub fn inc(&self, label: impl ToString) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_default() += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (value, count) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    /// Observations per bucket, the last one catches everything above
+    /// the largest bound.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match BUCKETS.get(i) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+                }
+                None => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+                }
+            }
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+    }
+}
+
+/// Renders every metric of `server`.
+pub fn render(server: &Server) -> String {
+    let metrics = &server.metrics;
+    let mut out = String::new();
+    metrics.queries_by_type.write(
+        &mut out,
+        "fake_dns_queries_by_type_total",
+        "Queries answered, by query type.",
+        "type",
+    );
+    metrics.queries_by_rcode.write(
+        &mut out,
+        "fake_dns_queries_by_rcode_total",
+        "Queries answered, by response code.",
+        "rcode",
+    );
+    metrics.queries_by_action.write(
+        &mut out,
+        "fake_dns_queries_by_action_total",
+        "Queries matched, by rule action.",
+        "action",
+    );
+    metrics.parse_failures.write(
+        &mut out,
+        "fake_dns_parse_failures_total",
+        "Requests that could not be parsed.",
+        "reason",
+    );
+    metrics.send_failures.write(
+        &mut out,
+        "fake_dns_send_failures_total",
+        "Responses that could not be sent.",
+        "transport",
+    );
+    metrics.latency.write(
+        &mut out,
+        "fake_dns_query_duration_seconds",
+        "Time from parsing a request to having its answer encoded.",
+    );
+
+    let mut pools = server
+        .mappings()
+        .map(|(name, mapping)| pool(name, "ipv4", &mapping.lock().unwrap()))
+        .collect::<Vec<_>>();
+    if let Some(mapping6) = &server.mapping6 {
+        pools.push(pool(DEFAULT_POOL, "ipv6", &mapping6.lock().unwrap()));
+    }
+    for (name, help, kind, field) in [
+        (
+            "fake_dns_pool_size",
+            "Addresses the pool can hand out.",
+            "gauge",
+            0,
+        ),
+        (
+            "fake_dns_pool_used",
+            "Addresses currently mapped.",
+            "gauge",
+            1,
+        ),
+        (
+            "fake_dns_pool_utilisation",
+            "Share of the pool currently mapped.",
+            "gauge",
+            2,
+        ),
+        (
+            "fake_dns_pool_evictions_total",
+            "Mappings evicted to make room.",
+            "counter",
+            3,
+        ),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (pool, family, values) in &pools {
+            let _ = writeln!(
+                out,
+                "{name}{{pool=\"{pool}\",family=\"{family}\"}} {}",
+                values[field]
+            );
+        }
+    }
+    out
+}
+
+/// Size, used, utilisation and evictions of one pool.
+fn pool<'a, P: Pool>(
+    name: &'a str,
+    family: &'static str,
+    mapping: &Mapping<P>,
+) -> (&'a str, &'static str, [f64; 4]) {
+    let (size, used) = (mapping.capacity() as f64, mapping.len() as f64);
+    (
+        name,
+        family,
+        [size, used, used / size, mapping.evictions() as f64],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hickory_resolver::proto::{op::Message, rr::RecordType, serialize::binary::BinEncodable};
+
+    use super::render;
+    use crate::{
+        Transport, respond,
+        tests::{CLIENT, request, server},
+    };
+
+    #[tokio::test]
+    async fn counts_queries() {
+        let server = server("10.0.0.0/29");
+        for name in ["a.example.com.", "b.example.com."] {
+            let bytes = request(name, RecordType::A).to_bytes().unwrap();
+            respond(&bytes, &server, CLIENT, Transport::Udp)
+                .await
+                .unwrap();
+        }
+        let bytes = request("a.example.com.", RecordType::MX)
+            .to_bytes()
+            .unwrap();
+        respond(&bytes, &server, CLIENT, Transport::Udp)
+            .await
+            .unwrap();
+        assert!(
+            respond(&[1, 2, 3], &server, CLIENT, Transport::Udp)
+                .await
+                .is_none()
+        );
+        let empty = Message::new().to_bytes().unwrap();
+        assert!(
+            respond(&empty, &server, CLIENT, Transport::Udp)
+                .await
+                .is_some()
+        );
+        server.metrics.latency.observe(Duration::from_secs(5));
+
+        let text = render(&server);
+        for line in [
+            "fake_dns_queries_by_type_total{type=\"A\"} 2",
+            "fake_dns_queries_by_type_total{type=\"MX\"} 1",
+            "fake_dns_queries_by_rcode_total{rcode=\"NoError\"} 3",
+            "fake_dns_queries_by_rcode_total{rcode=\"FormErr\"} 1",
+            "fake_dns_queries_by_action_total{action=\"fake\"} 3",
+            "fake_dns_parse_failures_total{reason=\"proto\"} 1",
+            "fake_dns_parse_failures_total{reason=\"empty_query\"} 1",
+            "fake_dns_query_duration_seconds_bucket{le=\"1\"} 4",
+            "fake_dns_query_duration_seconds_bucket{le=\"+Inf\"} 5",
+            "fake_dns_query_duration_seconds_count 5",
+            "fake_dns_pool_size{pool=\"default\",family=\"ipv4\"} 6",
+            "fake_dns_pool_used{pool=\"default\",family=\"ipv4\"} 2",
+            "fake_dns_pool_evictions_total{pool=\"default\",family=\"ipv4\"} 0",
+        ] {
+            assert!(text.contains(line), "missing `{line}` in\n{text}");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    fmt::{Display, Write},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use hickory_resolver::proto::rr::Name;
+use tokio::io::AsyncWriteExt;
+
+use crate::Server;
+
+/// Writes every mapping as an `<ip> <domain>` line, least recently used
+/// first, pinned mappings carry a trailing `pinned`. The file is replaced
+/// atomically so a crash never leaves a torn snapshot behind.
+pub async fn save(server: &Server, path: &Path) -> io::Result<()> {
+    let snapshot = snapshot(server);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(snapshot.as_bytes()).await?;
+    // on disk before it replaces the previous snapshot
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Restores a snapshot written by [`save`], each address into the pool
+/// holding it. Entries outside the configured pools, for example after
+/// `--cidr` changed, are dropped.
+pub async fn load(server: &Server, path: &Path) -> io::Result<()> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let (mut restored, mut skipped) = (0, 0);
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split(' ');
+        let entry = fields
+            .next()
+            .zip(fields.next())
+            .and_then(|(ip, name)| Some((ip.parse().ok()?, Name::from_ascii(name).ok()?)));
+        let pinned = fields.next() == Some("pinned");
+
+        let ok = match entry {
+            Some((IpAddr::V4(ip), name)) => server
+                .mappings()
+                .find(|(_, mapping)| mapping.lock().unwrap().contains(ip))
+                .is_some_and(|(_, mapping)| mapping.lock().unwrap().restore(&name, ip, pinned)),
+            Some((IpAddr::V6(ip), name)) => server
+                .mapping6
+                .as_ref()
+                .is_some_and(|mapping6| mapping6.lock().unwrap().restore(&name, ip, pinned)),
+            None => false,
+        };
+
+        if ok {
+            restored += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+
+    info!(
+        "restored {} mappings from {}, skipped {}",
+        restored,
+        path.display(),
+        skipped
+    );
+    Ok(())
+}
+
+/// Saves a snapshot every `interval`.
+pub async fn run(server: Arc<Server>, path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        if let Err(e) = save(&server, &path).await {
+            warn!("failed to save mappings to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+fn snapshot(server: &Server) -> String {
+    fn line(out: &mut String, ip: impl Display, name: &Name, pinned: bool) {
+        let pinned = if pinned { " pinned" } else { "" };
+        // Display renders IDNs in Unicode, which load() would not parse back
+        let _ = writeln!(out, "{ip} {}{pinned}", name.to_ascii());
+    }
+
+    let mut out = String::new();
+    for (_, mapping) in server.mappings() {
+        for (name, entry) in mapping.lock().unwrap().entries() {
+            line(&mut out, entry.ip, name, entry.pinned);
+        }
+    }
+    if let Some(mapping6) = &server.mapping6 {
+        for (name, entry) in mapping6.lock().unwrap().entries() {
+            line(&mut out, entry.ip, name, entry.pinned);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use hickory_resolver::proto::rr::Name;
+
+    use super::{load, save};
+    use crate::{
+        Server,
+        mapping::Mapping,
+        pool::{Ipv4, Ipv6},
+        tests::server,
+    };
+
+    fn dual_stack(cidr: &str) -> Server {
+        let mut server = server(cidr);
+        server.mapping6 = Some(Mutex::new(Mapping::new(
+            Ipv6::from_cidr("fd00::/64").unwrap(),
+        )));
+        let tenant = Ipv4::from_cidr("100.64.0.0/24").unwrap();
+        server
+            .pools
+            .insert("tenant-a".to_string(), Mutex::new(Mapping::new(tenant)));
+        server
+    }
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("fake-dns-{}.state", std::process::id()));
+        let a = Name::from_ascii("a.example.com.").unwrap();
+        let b = Name::from_ascii("b.example.com.").unwrap();
+        let idn = Name::from_ascii("xn--bcher-kva.example.").unwrap();
+
+        let before = dual_stack("10.0.0.0/24");
+        let ip_a = before.mapping.lock().unwrap().get_or_insert(&a).unwrap();
+        let ip_b = before.mapping.lock().unwrap().pin(&b, None).unwrap();
+        let ip_idn = before.mapping.lock().unwrap().get_or_insert(&idn).unwrap();
+        let mapping6 = before.mapping6.as_ref().unwrap();
+        let ip6_a = mapping6.lock().unwrap().get_or_insert(&a).unwrap();
+        let tenant = &before.pools["tenant-a"];
+        let ip_tenant = tenant.lock().unwrap().get_or_insert(&a).unwrap();
+        save(&before, &path).await.unwrap();
+
+        let after = dual_stack("10.0.0.0/24");
+        load(&after, &path).await.unwrap();
+        assert_eq!(after.mapping.lock().unwrap().domain(ip_a), Some(&a));
+        assert_eq!(after.mapping.lock().unwrap().domain(ip_idn), Some(&idn));
+        assert!(after.mapping.lock().unwrap().get(&b).unwrap().pinned);
+        assert_eq!(
+            after.mapping.lock().unwrap().get_or_insert(&b).unwrap(),
+            ip_b
+        );
+        let mapping6 = after.mapping6.as_ref().unwrap();
+        assert_eq!(mapping6.lock().unwrap().domain(ip6_a), Some(&a));
+        let tenant = &after.pools["tenant-a"];
+        assert_eq!(tenant.lock().unwrap().domain(ip_tenant), Some(&a));
+
+        // entries outside a changed pool are dropped
+        let moved = server("10.1.0.0/24");
+        load(&moved, &path).await.unwrap();
+        assert_eq!(moved.mapping.lock().unwrap().entries().count(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn temporary_file_beside_the_snapshot() {
+        let dir = std::env::temp_dir().join(format!("fake-dns-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let neighbour = dir.join("mappings.tmp");
+        std::fs::write(&neighbour, "keep").unwrap();
+
+        let server = dual_stack("10.0.0.0/24");
+        for path in [dir.join("mappings.state"), dir.join("mappings.state.tmp")] {
+            save(&server, &path).await.unwrap();
+            assert!(path.exists());
+        }
+        assert_eq!(std::fs::read_to_string(&neighbour).unwrap(), "keep");
+        assert!(!dir.join("mappings.state.tmp.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{Cli, MyError, Server, Settings, config::Config, rules::Action};
+
+/// How often the configuration and rule files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads the configuration on SIGHUP or when the configuration file, a
+/// rule list or a hosts file changes. Mappings and sockets are kept.
+pub async fn run(server: Arc<Server>, cli: Cli, mut config: Config) {
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to listen for SIGHUP");
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    let mut seen = modified(&cli, &config).await;
+
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = hangup.recv() => info!("reloading configuration on SIGHUP"),
+            _ = ticker.tick() => {
+                let now = modified(&cli, &config).await;
+                if now == seen {
+                    continue;
+                }
+                seen = now;
+                info!("reloading configuration, files changed");
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            ticker.tick().await;
+            let now = modified(&cli, &config).await;
+            if now == seen {
+                continue;
+            }
+            seen = now;
+            info!("reloading configuration, files changed");
+        }
+
+        match reload(&server, &cli, &config).await {
+            Ok(new) => {
+                config = new;
+                seen = modified(&cli, &config).await;
+            }
+            Err(e) => warn!("keeping the running configuration: {}", e),
+        }
+    }
+}
+
+/// Re-reads the configuration and rule files and swaps the new settings in.
+/// An invalid configuration is rejected and leaves `server` untouched.
+pub async fn reload(server: &Server, cli: &Cli, current: &Config) -> Result<Config, MyError> {
+    let config = Config::load(cli).await?;
+    let settings = Settings::new(&config).await?;
+    // named pools only start with the server
+    if let Some(pool) = config.pools.keys().find(|name| {
+        !server.pools.contains_key(*name) && settings.rules.any(&Action::Pool(name.to_string()))
+    }) {
+        return Err(MyError::Config(format!(
+            "rules use pool `{pool}`, which starts after a restart"
+        )));
+    }
+    *server.settings.write().unwrap() = Arc::new(settings);
+
+    for (section, changed) in [
+        ("server", config.server != current.server),
+        ("pool", config.pool != current.pool),
+        ("pools", config.pools != current.pools),
+        ("persist", config.persist != current.persist),
+        ("log", config.log != current.log),
+        ("admin", config.admin != current.admin),
+    ] {
+        if changed {
+            warn!("changes to [{}] take effect after a restart", section);
+        }
+    }
+    info!("configuration reloaded");
+    Ok(config)
+}
+
+/// Modification times of the watched files, `None` for missing ones.
+async fn modified(cli: &Cli, config: &Config) -> Vec<Option<SystemTime>> {
+    let paths: Vec<&PathBuf> = cli
+        .config
+        .iter()
+        .chain(&config.rules.files)
+        .chain(&config.hosts.files)
+        .collect();
+    let mut times = Vec::with_capacity(paths.len());
+    for path in paths {
+        let time = tokio::fs::metadata(path).await.and_then(|m| m.modified());
+        times.push(time.ok());
+    }
+    times
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use hickory_resolver::proto::rr::{Name, RecordType};
+
+    use super::reload;
+    use crate::{
+        Cli,
+        config::Config,
+        query,
+        rules::Action,
+        tests::{CLIENT, request, server},
+    };
+
+    #[tokio::test]
+    async fn swaps_rules_and_keeps_mappings() {
+        let dir = std::env::temp_dir().join(format!("fake-dns-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, rules) = (dir.join("config.toml"), dir.join("rules.txt"));
+        std::fs::write(
+            &path,
+            format!(
+                "[server]\nlisten = \"127.0.0.1:5353\"\n[pool]\ncidr = \"10.0.0.0/24\"\n\
+                 [rules]\nfiles = [{:?}]\n",
+                rules.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(&rules, "exact:ads.example.com block\n").unwrap();
+
+        let cli = Cli::parse_from(["fake-dns", "--config", path.to_str().unwrap()]);
+        let config = Config::load(&cli).await.unwrap();
+        let server = server("10.0.0.0/24");
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let ip = server.mapping.lock().unwrap().get_or_insert(&name).unwrap();
+
+        let config = reload(&server, &cli, &config).await.unwrap();
+        let ads = Name::from_ascii("ads.example.com.").unwrap();
+        assert_eq!(server.settings().rules.action(&ads).0, &Action::Block);
+
+        // a broken rule list is rejected, the previous rules stay in place
+        std::fs::write(&rules, "exact:ads.example.com nonsense\n").unwrap();
+        assert!(reload(&server, &cli, &config).await.is_err());
+        assert_eq!(server.settings().rules.action(&ads).0, &Action::Block);
+
+        std::fs::write(&rules, "exact:ads.example.com static:10.9.9.9\n").unwrap();
+        reload(&server, &cli, &config).await.unwrap();
+        let (response, _) = query(&request("www.example.com.", RecordType::A), &server, CLIENT)
+            .await
+            .unwrap();
+        assert_eq!(response.answers()[0].data().as_a().unwrap().0, ip);
+        assert_ne!(server.settings().rules.action(&ads).0, &Action::Block);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_rules_for_pools_not_running() {
+        let dir = std::env::temp_dir().join(format!("fake-dns-pools-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let write = |rules: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "[server]\nlisten = \"127.0.0.1:5353\"\n[pool]\ncidr = \"10.0.0.0/24\"\n\
+                     [pools.x]\ncidr = \"10.1.0.0/24\"\n\
+                     [rules]\nlist = [{rules}]\n"
+                ),
+            )
+            .unwrap();
+        };
+        write("\"exact:ads.example.com block\"");
+
+        let cli = Cli::parse_from(["fake-dns", "--config", path.to_str().unwrap()]);
+        let config = Config::load(&cli).await.unwrap();
+        let server = server("10.0.0.0/24");
+        // the new pool alone only waits for a restart
+        let config = reload(&server, &cli, &config).await.unwrap();
+        let ads = Name::from_ascii("ads.example.com.").unwrap();
+        assert_eq!(server.settings().rules.action(&ads).0, &Action::Block);
+
+        write("\"exact:ads.example.com fake:x\"");
+        let e = reload(&server, &cli, &config).await.unwrap_err();
+        assert!(e.to_string().contains("pool `x`"), "{e}");
+        assert_eq!(server.settings().rules.action(&ads).0, &Action::Block);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
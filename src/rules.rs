@@ -0,0 +1,292 @@
+use std::{net::IpAddr, str::FromStr};
+
+use clap::ValueEnum;
+use hickory_resolver::proto::rr::Name;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::MyError;
+
+/// What to do with a query once its name has been classified.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Answer with an address out of the default fake pool.
+    Fake,
+    /// Answer with an address out of the named IPv4 pool, see `[pools]`.
+    Pool(String),
+    /// Resolve for real through the upstream.
+    Forward,
+    /// Answer NXDOMAIN.
+    Block,
+    /// Answer with fixed addresses.
+    Static(Vec<IpAddr>),
+}
+
+impl Action {
+    /// Name used in metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Fake | Action::Pool(_) => "fake",
+            Action::Forward => "forward",
+            Action::Block => "block",
+            Action::Static(_) => "static",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fake" => Ok(Action::Fake),
+            None if s == "forward" => Ok(Action::Forward),
+            None if s == "block" => Ok(Action::Block),
+            Some(("fake", pool)) if !pool.is_empty() => Ok(Action::Pool(pool.to_string())),
+            Some(("static", ips)) => ips
+                .split(',')
+                .map(|ip| ip.trim().parse())
+                .collect::<Result<_, _>>()
+                .map(Action::Static)
+                .or(Err(MyError::Rule(format!(
+                    "invalid static addresses `{ips}`"
+                )))),
+            _ => Err(MyError::Rule(format!("unknown action `{s}`"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Matcher {
+    Exact(Name),
+    /// The domain itself and everything below it.
+    Suffix(Name),
+    Keyword(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn matches(&self, name: &Name, text: &str) -> bool {
+        match self {
+            Matcher::Exact(exact) => exact == name,
+            Matcher::Suffix(suffix) => suffix.zone_of(name),
+            Matcher::Keyword(keyword) => text.contains(keyword.as_str()),
+            Matcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+
+    /// Ordering used by [`Precedence::Specific`]: exact over suffix over
+    /// keyword over regex, longer patterns first within a kind.
+    fn specificity(&self) -> (u8, usize) {
+        match self {
+            Matcher::Exact(name) => (3, name.num_labels() as usize),
+            Matcher::Suffix(name) => (2, name.num_labels() as usize),
+            Matcher::Keyword(keyword) => (1, keyword.len()),
+            Matcher::Regex(_) => (0, 0),
+        }
+    }
+}
+
+/// A single `<kind>:<pattern> <action> [ttl=<seconds>]` line, e.g.
+/// `suffix:example.com forward` or `exact:nas.lan static:192.168.1.10 ttl=60`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub action: Action,
+    /// Overrides the configured TTLs of the answers this rule builds.
+    pub ttl: Option<u32>,
+}
+
+impl FromStr for Rule {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MyError::Rule(format!("invalid rule `{s}`"));
+        let (matcher, action) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let (kind, pattern) = matcher.split_once(':').ok_or_else(invalid)?;
+
+        let matcher = match kind {
+            "exact" => Matcher::Exact(fqdn(pattern)?),
+            "suffix" => Matcher::Suffix(fqdn(pattern)?),
+            "keyword" => Matcher::Keyword(pattern.to_ascii_lowercase()),
+            "regex" => Matcher::Regex(
+                Regex::new(pattern)
+                    .map_err(|e| MyError::Rule(format!("invalid regex `{pattern}`: {e}")))?,
+            ),
+            _ => return Err(invalid()),
+        };
+
+        let (action, ttl) = match action.trim().rsplit_once(char::is_whitespace) {
+            Some((action, ttl)) if ttl.starts_with("ttl=") => {
+                let ttl = ttl[4..]
+                    .parse()
+                    .or(Err(MyError::Rule(format!("invalid ttl `{ttl}`"))))?;
+                (action, Some(ttl))
+            }
+            _ => (action, None),
+        };
+
+        Ok(Self {
+            matcher,
+            action: action.trim().parse()?,
+            ttl,
+        })
+    }
+}
+
+/// Parses `domain` as a fully qualified name, the trailing dot is optional.
+pub fn fqdn(domain: &str) -> Result<Name, MyError> {
+    let mut name = Name::from_str(domain)
+        .or(Err(MyError::Rule(format!("invalid domain `{domain}`"))))?
+        .to_lowercase();
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    /// The first rule in declaration order wins.
+    #[default]
+    First,
+    /// The most specific matching rule wins: exact over suffix over keyword
+    /// over regex, longer patterns first.
+    Specific,
+}
+
+pub struct Rules {
+    rules: Vec<Rule>,
+    precedence: Precedence,
+    /// Action for names no rule matches.
+    default: Action,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>, precedence: Precedence, default: Action) -> Self {
+        Self {
+            rules,
+            precedence,
+            default,
+        }
+    }
+
+    /// Parses a rule list, one rule per line, `#` starts a comment.
+    pub fn parse_list(list: &str) -> Result<Vec<Rule>, MyError> {
+        list.lines()
+            .enumerate()
+            .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                line.parse().map_err(|e| match e {
+                    MyError::Rule(msg) => MyError::Rule(format!("line {}: {msg}", i + 1)),
+                    e => e,
+                })
+            })
+            .collect()
+    }
+
+    /// Action for `name` and the TTL override of the rule that chose it.
+    pub fn action(&self, name: &Name) -> (&Action, Option<u32>) {
+        let text = name.to_lowercase().to_ascii();
+        let text = text.strip_suffix('.').unwrap_or(&text);
+        let mut matching = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matcher.matches(name, text));
+
+        let rule = match self.precedence {
+            Precedence::First => matching.next(),
+            Precedence::Specific => matching.fold(None, |best: Option<&Rule>, rule| match best {
+                Some(best) if best.matcher.specificity() >= rule.matcher.specificity() => {
+                    Some(best)
+                }
+                _ => Some(rule),
+            }),
+        };
+
+        rule.map_or((&self.default, None), |rule| (&rule.action, rule.ttl))
+    }
+
+    pub fn any(&self, action: &Action) -> bool {
+        &self.default == action || self.rules.iter().any(|rule| &rule.action == action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::rr::Name;
+
+    use super::{Action, Precedence, Rules};
+
+    const LIST: &str = "
+        # comment
+        suffix:example.com forward
+        exact:ads.example.com block
+        keyword:tracker block   # trailing comment
+        regex:^cdn[0-9]+\\. static:192.0.2.1,2001:db8::1
+        exact:nas.lan static:192.168.1.10, 192.168.1.11 ttl=60
+        suffix:corp.example.org fake:tenant-a
+    ";
+
+    fn action(rules: &Rules, name: &str) -> Action {
+        rules.action(&Name::from_ascii(name).unwrap()).0.clone()
+    }
+
+    #[test]
+    fn first_match() {
+        let rules = Rules::new(
+            Rules::parse_list(LIST).unwrap(),
+            Precedence::First,
+            Action::Fake,
+        );
+        assert_eq!(action(&rules, "ADS.example.com."), Action::Forward);
+        assert_eq!(action(&rules, "www.example.com."), Action::Forward);
+        assert_eq!(action(&rules, "my-tracker.net."), Action::Block);
+        assert_eq!(
+            action(&rules, "cdn12.example.org."),
+            Action::Static(vec![
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ])
+        );
+        assert_eq!(action(&rules, "example.org."), Action::Fake);
+        assert_eq!(
+            action(&rules, "git.corp.example.org."),
+            Action::Pool("tenant-a".to_string())
+        );
+
+        let nas = Name::from_ascii("nas.lan.").unwrap();
+        assert_eq!(rules.action(&nas).1, Some(60));
+        assert_eq!(rules.action(&Name::from_ascii("lan.").unwrap()).1, None);
+    }
+
+    #[test]
+    fn most_specific() {
+        let rules = Rules::new(
+            Rules::parse_list(LIST).unwrap(),
+            Precedence::Specific,
+            Action::Fake,
+        );
+        assert_eq!(action(&rules, "ads.example.com."), Action::Block);
+        assert_eq!(action(&rules, "tracker.example.com."), Action::Forward);
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for list in [
+            "suffix:example.com",
+            "glob:*.com fake",
+            "exact:a.com drop",
+            "regex:( fake",
+            "exact:a.com fake ttl=soon",
+            "exact:a.com fake:",
+        ] {
+            assert!(Rules::parse_list(list).is_err(), "{list}");
+        }
+        let e = Rules::parse_list("exact:a.com fake\n\nexact:b.com nope").unwrap_err();
+        assert!(e.to_string().contains("line 3"), "{e}");
+    }
+}
@@ -0,0 +1,173 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{Instant, timeout_at},
+};
+
+use crate::{Server, Transport, respond};
+
+/// Connections without outstanding queries are closed after this long, see
+/// RFC 7766 section 6.2.3. A query has to arrive completely within this long
+/// of its first byte, whatever else is in flight.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts DNS over TCP connections, RFC 1035 section 4.2.2 framing.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, client)) => {
+                tokio::spawn(connection(stream, client, server.clone()));
+            }
+            Err(e) => warn!("failed to accept tcp connection {:?}", e),
+        }
+    }
+}
+
+/// Serves one connection. Every query is answered on its own task so
+/// pipelined queries may be answered out of order.
+async fn connection(stream: TcpStream, client: SocketAddr, server: Arc<Server>) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+
+    let write = tokio::spawn({
+        let server = server.clone();
+        async move {
+            while let Some(response) = rx.recv().await {
+                let Ok(len) = u16::try_from(response.len()) else {
+                    server.metrics.send_failures.inc("tcp");
+                    warn!("dropping {} byte tcp response", response.len());
+                    continue;
+                };
+                if let Err(e) = writer
+                    .write_all(&[&len.to_be_bytes(), &response[..]].concat())
+                    .await
+                {
+                    server.metrics.send_failures.inc("tcp");
+                    warn!("failed to send dns response {:?}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    // survives timeouts, a length prefix may arrive a byte at a time
+    let mut buf = Vec::new();
+    // when the first byte of a partly read query arrived
+    let mut started = None;
+    loop {
+        while let Some(request) = take_message(&mut buf) {
+            started = None;
+            let permit = server.concurrency.clone().acquire_owned().await.unwrap();
+            let (server, tx) = (server.clone(), tx.clone());
+            tokio::spawn(async move {
+                if let Some(response) = respond(&request, &server, client, Transport::Tcp).await {
+                    let _ = tx.send(response).await;
+                }
+                drop(permit);
+            });
+        }
+        if buf.is_empty() {
+            started = None;
+        } else {
+            started.get_or_insert_with(Instant::now);
+        }
+
+        let deadline = started.unwrap_or_else(Instant::now) + IDLE_TIMEOUT;
+        match timeout_at(deadline, reader.read_buf(&mut buf)).await {
+            Ok(Ok(0) | Err(_)) => break,
+            Ok(Ok(_)) => {}
+            // queries still in flight hold a sender, the connection is not idle
+            Err(_) if started.is_none() && tx.strong_count() > 1 => {}
+            Err(_) => break,
+        }
+    }
+
+    drop(tx);
+    let _ = write.await;
+}
+
+/// Splits the first complete length prefixed message off `buf`.
+fn take_message(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+    if buf.len() < 2 + len {
+        return None;
+    }
+    let message = buf[2..2 + len].to_vec();
+    buf.drain(..2 + len);
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use hickory_resolver::proto::{
+        op::Message,
+        rr::RecordType,
+        serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::{Instant, sleep},
+    };
+
+    use crate::tests::{request, server};
+
+    #[tokio::test]
+    async fn pipelined_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, Arc::new(server("10.0.0.0/24"))));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut ids = vec![];
+        for (id, name) in [(1, "a.example.com."), (2, "b.example.com.")] {
+            let mut message = request(name, RecordType::A);
+            message.set_id(id);
+            let bytes = message.to_bytes().unwrap();
+            stream.write_u16(bytes.len() as u16).await.unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            ids.push(id);
+        }
+
+        for _ in 0..2 {
+            let len = stream.read_u16().await.unwrap();
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await.unwrap();
+            let response = Message::from_bytes(&response).unwrap();
+            ids.retain(|id| *id != response.id());
+            assert_eq!(response.answers().len(), 1);
+        }
+        assert!(ids.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_queries_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(super::serve(listener, Arc::new(server("10.0.0.0/24"))));
+
+        let bytes = request("a.example.com.", RecordType::A).to_bytes().unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // the length prefix split across reads
+        stream.write_u8(0).await.unwrap();
+        sleep(Duration::from_secs(1)).await;
+        stream.write_u8(bytes.len() as u8).await.unwrap();
+        stream.write_all(&bytes).await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(Message::from_bytes(&response).unwrap().answers().len(), 1);
+
+        // a length prefix and then nothing
+        stream.write_u16(bytes.len() as u16).await.unwrap();
+        stream.write_all(&bytes[..4]).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(started.elapsed() >= super::IDLE_TIMEOUT);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::{Server, Transport, respond};
+
+/// Answers queries arriving on `socket`, each on its own task.
+///
+/// Receiving pauses while `server.concurrency` has no permits left, so a
+/// slow upstream applies backpressure instead of piling up tasks.
+pub async fn serve(socket: Arc<UdpSocket>, server: Arc<Server>) -> io::Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+
+    loop {
+        let (size, src) = socket.recv_from(&mut buf).await?;
+        let request = buf[..size].to_vec();
+
+        let permit = server.concurrency.clone().acquire_owned().await.unwrap();
+        let (socket, server) = (socket.clone(), server.clone());
+        tokio::spawn(async move {
+            if let Some(b) = respond(&request, &server, src, Transport::Udp).await
+                && let Err(e) = socket.send_to(&b, &src).await
+            {
+                server.metrics.send_failures.inc("udp");
+                warn!("failed to send dns response {:?}", e)
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Binds a socket with SO_REUSEPORT so several workers can share `addr`
+/// and the kernel spreads datagrams between them.
+pub fn bind_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hickory_resolver::proto::{
+        op::Message,
+        rr::RecordType,
+        serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use tokio::net::UdpSocket;
+
+    use super::{bind_reuse_port, serve};
+    use crate::tests::{request, server};
+
+    #[tokio::test]
+    async fn reuse_port_workers() {
+        let server = Arc::new(server("10.0.0.0/24"));
+        let first = bind_reuse_port("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_reuse_port(addr).unwrap();
+        tokio::spawn(serve(Arc::new(first), server.clone()));
+        tokio::spawn(serve(Arc::new(second), server));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for name in ["a.example.com.", "b.example.com."] {
+            let bytes = request(name, RecordType::A).to_bytes().unwrap();
+            client.send_to(&bytes, addr).await.unwrap();
+            let size = client.recv(&mut buf).await.unwrap();
+            let response = Message::from_bytes(&buf[..size]).unwrap();
+            assert_eq!(response.answers().len(), 1);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::SocketAddr;
+
+use hickory_resolver::{
+    ResolveError, Resolver, TokioResolver,
+    config::{NameServerConfig, NameServerConfigGroup, ResolveHosts, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::{
+        ProtoError, ProtoErrorKind,
+        op::{Message, Query, ResponseCode},
+        xfer::Protocol,
+    },
+};
+
+/// Real resolver used for everything that is not answered with a fake ip.
+pub struct Upstream {
+    resolver: TokioResolver,
+}
+
+impl Upstream {
+    pub fn new(servers: &[SocketAddr]) -> Self {
+        let mut group = NameServerConfigGroup::with_capacity(servers.len() * 2);
+        for server in servers {
+            group.push(NameServerConfig::new(*server, Protocol::Udp));
+            group.push(NameServerConfig::new(*server, Protocol::Tcp));
+        }
+
+        let config = ResolverConfig::from_parts(None, vec![], group);
+        let mut builder = Resolver::builder_with_config(config, TokioConnectionProvider::default());
+        builder.options_mut().use_hosts_file = ResolveHosts::Never;
+
+        Self {
+            resolver: builder.build(),
+        }
+    }
+
+    /// Resolves `query` upstream and relays the outcome into `response`.
+    ///
+    /// Negative answers keep their response code and SOA, anything else that
+    /// goes wrong becomes SERVFAIL. Relayed answers are not authoritative and
+    /// records owned by the queried name carry its exact spelling.
+    pub async fn forward(&self, query: &Query, response: &mut Message) {
+        response.set_authoritative(false);
+        match self
+            .resolver
+            .lookup(query.name().clone(), query.query_type())
+            .await
+        {
+            Ok(lookup) => {
+                response.add_answers(lookup.records().iter().map(|record| {
+                    let mut record = record.clone();
+                    if record.name() == query.name() {
+                        record.set_name(query.name().clone());
+                    }
+                    record
+                }));
+            }
+            Err(e) if negative(&e) => {
+                if e.is_nx_domain() {
+                    response.set_response_code(ResponseCode::NXDomain);
+                }
+                if let Some(soa) = e.into_soa() {
+                    response.add_name_server(soa.into_record_of_rdata());
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "failed to forward {} {}: {}",
+                    query.name(),
+                    query.query_type(),
+                    e
+                );
+                response.set_response_code(ResponseCode::ServFail);
+            }
+        }
+    }
+}
+
+/// Whether `e` is a NODATA or NXDOMAIN answer, the resolver reports a
+/// SERVFAIL or REFUSED from upstream as "no records found" too.
+fn negative(e: &ResolveError) -> bool {
+    matches!(
+        e.proto().map(ProtoError::kind),
+        Some(ProtoErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError | ResponseCode::NXDomain,
+            ..
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hickory_resolver::proto::{
+        op::{Message, MessageType, Query, ResponseCode},
+        rr::{Name, RData, Record, RecordType},
+        serialize::binary::{BinDecodable, BinEncodable},
+    };
+    use tokio::net::UdpSocket;
+
+    use super::Upstream;
+
+    /// Answers `ok.test.` with an address, `missing.test.` with NXDOMAIN and
+    /// a SOA, and everything else with SERVFAIL.
+    async fn stub() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_bytes(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+                match query.name().to_lowercase().to_ascii().as_str() {
+                    "ok.test." => {
+                        let ip = RData::A("192.0.2.1".parse().unwrap());
+                        response.add_answer(Record::from_rdata(query.name().clone(), 60, ip));
+                    }
+                    "missing.test." => {
+                        response.set_response_code(ResponseCode::NXDomain);
+                        response
+                            .add_name_server(crate::soa(&Name::from_ascii("test.").unwrap(), 60));
+                    }
+                    _ => {
+                        response.set_response_code(ResponseCode::ServFail);
+                    }
+                }
+                socket
+                    .send_to(&response.to_bytes().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn forward(upstream: &Upstream, name: &str) -> Message {
+        let query = Query::query(Name::from_ascii(name).unwrap(), RecordType::A);
+        let mut response = Message::new();
+        response.set_authoritative(true);
+        upstream.forward(&query, &mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn relays_answers_and_failures() {
+        let upstream = Upstream::new(&[stub().await]);
+
+        let response = forward(&upstream, "OK.test.").await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(!response.authoritative());
+        assert_eq!(response.answers()[0].name().to_ascii(), "OK.test.");
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A("192.0.2.1".parse().unwrap())
+        );
+
+        let response = forward(&upstream, "missing.test.").await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+
+        let response = forward(&upstream, "broken.test.").await;
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(response.answers().is_empty());
+    }
+}